@@ -0,0 +1,15 @@
+use thiserror::Error;
+
+/// Errors returned by the beacon node fetchers
+#[derive(Debug, Error)]
+pub enum BeaconError {
+    /// The request to the beacon node failed or returned a non-success status
+    #[error("Beacon node request failed")]
+    Request(#[from] reqwest::Error),
+    /// The beacon node response could not be deserialized
+    #[error("Invalid beacon node response")]
+    Decoding(#[from] serde_json::Error),
+    /// The block at the requested slot is not an Electra block
+    #[error("Block at slot {0} is not an Electra block")]
+    UnexpectedFork(u64),
+}
@@ -2,6 +2,8 @@
 use consensus_types::{BeaconBlockHeader, SignedBeaconBlock};
 #[cfg(feature = "no-zkvm")]
 use consensus_types::{MainnetEthSpec, SignedBeaconBlockElectra};
+#[cfg(feature = "no-zkvm")]
+pub use error::BeaconError;
 use helpers::merkleize_container;
 use itertools::Itertools;
 #[cfg(feature = "no-zkvm")]
@@ -9,6 +11,8 @@ use tree_hash::TreeHash;
 use types::electra::ElectraBlockHeader;
 #[cfg(feature = "no-zkvm")]
 use types::electra::{ElectraBlockBodyPayloadRoots, ElectraBlockBodyRoots};
+#[cfg(feature = "no-zkvm")]
+pub mod error;
 pub mod helpers;
 pub mod types;
 
@@ -61,22 +65,22 @@ pub fn merkleize_header(header: ElectraBlockHeader) -> [u8; 32] {
 ///
 /// # Errors
 /// Returns an error if the request fails or the response cannot be parsed
-pub async fn get_beacon_block_header(slot: u64, url: &str) -> BeaconBlockHeader {
+pub async fn get_beacon_block_header(
+    slot: u64,
+    url: &str,
+) -> Result<BeaconBlockHeader, BeaconError> {
     let client = reqwest::Client::new();
     let url = format!("{}/eth/v1/beacon/headers/{}", url, slot);
     let resp = client
         .get(&url)
         .send()
-        .await
-        .unwrap()
-        .error_for_status()
-        .unwrap()
+        .await?
+        .error_for_status()?
         .json::<serde_json::Value>()
-        .await
-        .unwrap();
+        .await?;
     let summary: BeaconBlockHeader =
-        serde_json::from_value(resp["data"]["header"]["message"].clone()).unwrap();
-    summary
+        serde_json::from_value(resp["data"]["header"]["message"].clone())?;
+    Ok(summary)
 }
 
 #[cfg(feature = "no-zkvm")]
@@ -90,24 +94,23 @@ pub async fn get_beacon_block_header(slot: u64, url: &str) -> BeaconBlockHeader
 /// The requested Electra block
 ///
 /// # Errors
-/// Returns an error if the request fails or the response cannot be parsed
-pub async fn get_electra_block(slot: u64, url: &str) -> SignedBeaconBlockElectra<MainnetEthSpec> {
+/// Returns an error if the request fails, the response cannot be parsed or the block at `slot`
+/// is not an Electra block
+pub async fn get_electra_block(
+    slot: u64,
+    url: &str,
+) -> Result<SignedBeaconBlockElectra<MainnetEthSpec>, BeaconError> {
     let endpoint = format!("{}/eth/v2/beacon/blocks/{}", url, slot);
     let client = reqwest::Client::new();
-    let resp = client
-        .get(endpoint)
-        .send()
-        .await
-        .expect("Request failed")
-        .error_for_status()
-        .expect("Non-200 response");
+    let resp = client.get(endpoint).send().await?.error_for_status()?;
 
-    let json: serde_json::Value = resp.json().await.expect("Invalid JSON");
+    let json: serde_json::Value = resp.json().await?;
     let block_data = json["data"].clone();
-    let block: SignedBeaconBlock<MainnetEthSpec> =
-        serde_json::from_value(block_data).expect("Deserialization failed");
-    let electra_block = block.as_electra().unwrap();
-    electra_block.clone()
+    let block: SignedBeaconBlock<MainnetEthSpec> = serde_json::from_value(block_data)?;
+    let electra_block = block
+        .as_electra()
+        .map_err(|_| BeaconError::UnexpectedFork(slot))?;
+    Ok(electra_block.clone())
 }
 
 #[cfg(feature = "no-zkvm")]
@@ -182,55 +185,3 @@ pub fn extract_electra_block_body(
             .into(),
     }
 }
-
-#[cfg(feature = "no-zkvm")]
-#[tokio::test]
-/// Tests the functionality of fetching and processing beacon block bodies
-///
-/// This test verifies that:
-/// 1. We can fetch a beacon block header
-/// 2. We can fetch and process the corresponding block
-/// 3. The computed merkle roots match the expected values
-async fn test_get_beacon_block_body() {
-    let beacon_block_header =
-        get_beacon_block_header(7520257, "https://lodestar-sepolia.chainsafe.io").await;
-    // Lodestar Sepolia endpoint
-    let endpoint = format!(
-        "https://lodestar-sepolia.chainsafe.io/eth/v2/beacon/blocks/{}",
-        7520257
-    );
-    // Fetch the latest block
-    let client = reqwest::Client::new();
-    let resp = client
-        .get(endpoint)
-        .send()
-        .await
-        .expect("Request failed")
-        .error_for_status()
-        .expect("Non-200 response");
-
-    let json: serde_json::Value = resp.json().await.expect("Invalid JSON");
-    let block_data = json["data"].clone();
-    let block: SignedBeaconBlock<MainnetEthSpec> =
-        serde_json::from_value(block_data).expect("Deserialization failed");
-    let electra_block = block.as_electra().unwrap();
-    let electra_block_body = extract_electra_block_body(electra_block.clone());
-    let electra_block_body_root = electra_block_body.merkelize();
-
-    assert_eq!(
-        electra_block_body_root.to_vec(),
-        beacon_block_header.body_root.to_vec()
-    );
-    let electra_block_header = ElectraBlockHeader {
-        slot: beacon_block_header.slot.as_u64(),
-        proposer_index: beacon_block_header.proposer_index,
-        parent_root: beacon_block_header.parent_root.into(),
-        state_root: beacon_block_header.state_root.into(),
-        body_root: beacon_block_header.body_root.into(),
-    };
-    let beacon_block_header_root = merkleize_header(electra_block_header);
-    assert_eq!(
-        beacon_block_header_root.to_vec(),
-        beacon_block_header.tree_hash_root().to_vec()
-    );
-}
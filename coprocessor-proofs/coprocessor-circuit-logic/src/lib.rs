@@ -1,79 +1,251 @@
 use alloy_sol_types::{SolType, sol};
-use sp1_verifier::Groth16Verifier;
+use sp1_verifier::{Groth16Verifier, PlonkVerifier};
 use tendermint_program_types::TendermintOutput;
-use types::CoprocessorCircuitInputs;
+use types::{
+    CoprocessorCircuitInputs, CoprocessorCircuitOutputs, DomainOpenings, DomainTransition,
+    LightClientKind, LightClientProof, ProofMode, SmtContext, SmtInsertion, SmtKey, TrustedState,
+};
 use valence_coprocessor_core::MemorySmt;
 
-pub fn coprocessor_logic(inputs: CoprocessorCircuitInputs) -> [u8; 32] {
-    let neutron_output: TendermintOutput =
-        serde_json::from_slice(&inputs.neutron_public_values).unwrap();
-    let helios_output: ProofOutputs =
-        ProofOutputs::abi_decode(&inputs.helios_public_values, false).unwrap();
-    // assert the trusted values
-    assert!(inputs.previous_neutron_height < neutron_output.target_height);
-    assert!(inputs.previous_ethereum_height < helios_output.newHead.try_into().unwrap());
-    assert!(inputs.previous_neutron_root == neutron_output.target_header_hash.to_vec());
-    assert!(inputs.previous_ethereum_root == helios_output.prevHeader.to_vec());
-
-    // these are the targets that we want to insert and commit
-    let target_neutron_height: u64 = neutron_output.target_height;
-    let target_neutron_root: Vec<u8> = neutron_output.target_header_hash.to_vec();
-    let target_ethereum_height: u64 = helios_output.newHead.try_into().unwrap();
-    let target_ethereum_root: Vec<u8> = helios_output.newHeader.to_vec();
-
-    // verify the smt inserts of these targets
-    MemorySmt::verify(
-        "demo",
+pub fn coprocessor_logic(inputs: CoprocessorCircuitInputs) -> CoprocessorCircuitOutputs {
+    // the SP1 groth16 and plonk verification keys
+    let groth16_vk: &[u8] = *sp1_verifier::GROTH16_VK_BYTES;
+    let plonk_vk: &[u8] = *sp1_verifier::PLONK_VK_BYTES;
+    let mut domains: Vec<DomainTransition> = Vec::new();
+    for update in inputs.domain_updates {
+        let domain_id = update.openings.domain_id.clone();
+        assert!(
+            domains.iter().all(|domain| domain.domain_id != domain_id),
+            "Duplicate update for domain {}",
+            domain_id
+        );
+        // these are the targets that we want to insert and commit, every proof of the update
+        // has to start from the state proven by the one before
+        assert!(
+            !update.proofs.is_empty(),
+            "Missing light client proof for domain {}",
+            domain_id
+        );
+        // the light client program is not known to the circuit, so all proofs must come from one
+        // program, whose key is committed for the verifier to pin
+        let vk = update.proofs[0].vk.clone();
+        let mut target = update.previous.clone();
+        for proof in &update.proofs {
+            assert_eq!(
+                proof.vk, vk,
+                "Light client proofs of domain {} use different programs",
+                domain_id
+            );
+            target = match update.kind {
+                LightClientKind::Tendermint => tendermint_target(&target, proof),
+                LightClientKind::Helios => helios_target(&target, proof),
+            };
+            // verify the light client proof
+            match proof.proof_mode {
+                ProofMode::Groth16 => Groth16Verifier::verify(
+                    &proof.proof,
+                    &proof.public_values,
+                    &proof.vk,
+                    groth16_vk,
+                )
+                .expect("Failed to verify zk light client update"),
+                ProofMode::Plonk => {
+                    PlonkVerifier::verify(&proof.proof, &proof.public_values, &proof.vk, plonk_vk)
+                        .expect("Failed to verify zk light client update")
+                }
+            }
+        }
+
+        // the light client update must start from the state stored under the previous root,
+        // unless this update bootstraps the domain or re-anchors it
+        let previous = update.previous_openings.as_ref().map(|previous_openings| {
+            assert_eq!(previous_openings.domain_id, domain_id);
+            let stored = stored_state(previous_openings);
+            verify_domain_openings(
+                &inputs.previous_coprocessor_root,
+                previous_openings,
+                &stored,
+            );
+            stored
+        });
+        match &previous {
+            Some(stored) if update.reanchored => assert!(
+                stored.height < update.previous.height,
+                "Re-anchor of domain {} does not advance its state",
+                domain_id
+            ),
+            Some(stored) => assert_eq!(*stored, update.previous),
+            // a domain without state is bootstrapped, the absence of its keys is checked with
+            // the inserts
+            None => assert!(
+                !update.reanchored,
+                "Re-anchor of domain {} does not open the state it replaces",
+                domain_id
+            ),
+        }
+
+        // verify the smt inserts of these targets
+        verify_domain_openings(&inputs.coprocessor_root, &update.openings, &target);
+
+        // an update that is not chained to the previous state exposes the state it starts from
+        let anchor = (previous.is_none() || update.reanchored).then(|| update.previous.clone());
+        domains.push(DomainTransition {
+            domain_id,
+            previous,
+            anchor,
+            reanchored: update.reanchored,
+            target,
+            vk,
+        });
+    }
+    // the new root must be the previous root with exactly the witnessed inserts applied
+    verify_insertions(
+        &inputs.previous_coprocessor_root,
+        &inputs.insertions,
         &inputs.coprocessor_root,
-        &inputs.neutron_height_opening,
+        &domains,
     );
+    CoprocessorCircuitOutputs {
+        previous_coprocessor_root: inputs.previous_coprocessor_root,
+        coprocessor_root: inputs.coprocessor_root,
+        domains,
+    }
+}
+
+/// Verifies that `coprocessor_root` is derived from `previous_root` by applying exactly `insertions`
+///
+/// Every insert is proven by verifying the opening of the new value, with the data swapped back
+/// to the previous value, against the root before the insert. For a new key the previous value
+/// is the empty leaf, so the opening proves that the key was absent. Both roots then share every
+/// sibling on the path of the key, so every other key (including the data of other applications)
+/// is preserved.
+///
+/// The keys of a bootstrapped domain must be written, and be new when they are first written,
+/// which proves that the domain had no state under the previous root.
+fn verify_insertions(
+    previous_root: &[u8; 32],
+    insertions: &[SmtInsertion],
+    coprocessor_root: &[u8; 32],
+    domains: &[DomainTransition],
+) {
+    let mut root = *previous_root;
+    let mut written = Vec::new();
+    for insertion in insertions {
+        assert!(MemorySmt::verify(
+            &insertion.context,
+            &insertion.root,
+            &insertion.opening
+        ));
+        let mut previous_opening = insertion.opening.clone();
+        previous_opening.data = insertion.previous_data.clone().unwrap_or_default();
+        assert!(
+            MemorySmt::verify(&insertion.context, &root, &previous_opening),
+            "SMT insert does not extend the previous root"
+        );
+        // light client values may only be written by the domain updates proven above
+        if insertion.context == SmtContext::LightClients.name() {
+            let key = insertion.opening.key;
+            let data = &insertion.opening.data;
+            let domain = domains
+                .iter()
+                .find(|domain| {
+                    (key == SmtKey::DomainHeight(&domain.domain_id).derive()
+                        && *data == domain.target.height.to_be_bytes())
+                        || (key == SmtKey::DomainRoot(&domain.domain_id).derive()
+                            && *data == domain.target.root)
+                })
+                .expect("Unproven write to the light client context");
+            if domain.previous.is_none() && !written.contains(&key) {
+                assert!(
+                    insertion.previous_data.is_none(),
+                    "Bootstrapped domain {} has state under the previous root",
+                    domain.domain_id
+                );
+            }
+            written.push(key);
+        }
+        root = insertion.root;
+    }
+    assert_eq!(root, *coprocessor_root);
+    for domain in domains.iter().filter(|domain| domain.previous.is_none()) {
+        assert!(
+            written.contains(&SmtKey::DomainHeight(&domain.domain_id).derive())
+                && written.contains(&SmtKey::DomainRoot(&domain.domain_id).derive()),
+            "Missing inserts of bootstrapped domain {}",
+            domain.domain_id
+        );
+    }
+}
+
+/// Decodes the height and root stored for a domain from its openings
+fn stored_state(openings: &DomainOpenings) -> TrustedState {
+    TrustedState {
+        height: u64::from_be_bytes(
+            openings
+                .height_opening
+                .data
+                .as_slice()
+                .try_into()
+                .expect("Invalid stored domain height"),
+        ),
+        root: openings.root_opening.data.clone(),
+    }
+}
+
+/// Verifies that the height and root of a domain are stored under the keys of that domain,
+/// in the context reserved for the light clients, and that they match `state`
+fn verify_domain_openings(root: &[u8; 32], openings: &DomainOpenings, state: &TrustedState) {
     assert_eq!(
-        &inputs.neutron_height_opening.data,
-        &target_neutron_height.to_be_bytes().to_vec()
-    );
-    MemorySmt::verify(
-        "demo",
-        &inputs.coprocessor_root,
-        &inputs.ethereum_height_opening,
+        openings.height_opening.key,
+        SmtKey::DomainHeight(&openings.domain_id).derive()
     );
+    assert!(MemorySmt::verify(
+        SmtContext::LightClients.name(),
+        root,
+        &openings.height_opening
+    ));
     assert_eq!(
-        &inputs.ethereum_height_opening.data,
-        &target_ethereum_height.to_be_bytes().to_vec()
+        &openings.height_opening.data,
+        &state.height.to_be_bytes().to_vec()
     );
-    MemorySmt::verify(
-        "demo",
-        &inputs.coprocessor_root,
-        &inputs.neutron_root_opening,
-    );
-    assert_eq!(&inputs.neutron_root_opening.data, &target_neutron_root);
-    MemorySmt::verify(
-        "demo",
-        &inputs.coprocessor_root,
-        &inputs.ethereum_root_opening,
+    assert_eq!(
+        openings.root_opening.key,
+        SmtKey::DomainRoot(&openings.domain_id).derive()
     );
-    assert_eq!(&inputs.ethereum_root_opening.data, &target_ethereum_root);
+    assert!(MemorySmt::verify(
+        SmtContext::LightClients.name(),
+        root,
+        &openings.root_opening
+    ));
+    assert_eq!(&openings.root_opening.data, &state.root);
+}
 
-    // the SP1 groth16 verification key
-    let groth16_vk: &[u8] = *sp1_verifier::GROTH16_VK_BYTES;
+/// Checks a Tendermint light client proof against the previous state and returns the new height and header hash
+fn tendermint_target(previous: &TrustedState, proof: &LightClientProof) -> TrustedState {
+    let output: TendermintOutput = serde_json::from_slice(&proof.public_values).unwrap();
+    // assert the trusted values
+    assert_eq!(previous.height, output.trusted_height);
+    assert_eq!(previous.root, output.trusted_header_hash.to_vec());
+    assert!(previous.height < output.target_height);
+    TrustedState {
+        height: output.target_height,
+        root: output.target_header_hash.to_vec(),
+    }
+}
 
-    // verify the neutron update proof
-    Groth16Verifier::verify(
-        &inputs.neutron_proof,
-        &inputs.neutron_public_values,
-        &inputs.neutron_vk,
-        groth16_vk,
-    )
-    .expect("Failed to verify neutron zk light client update");
-
-    // verify the ethereum update proof
-    Groth16Verifier::verify(
-        &inputs.helios_proof,
-        &inputs.helios_public_values,
-        &inputs.helios_vk,
-        groth16_vk,
-    )
-    .expect("Failed to verify helios zk light client update");
-    inputs.coprocessor_root
+/// Checks a Helios light client proof against the previous state and returns the new slot and header root
+fn helios_target(previous: &TrustedState, proof: &LightClientProof) -> TrustedState {
+    let output: ProofOutputs = ProofOutputs::abi_decode(&proof.public_values, false).unwrap();
+    let previous_head: u64 = output.prevHead.try_into().unwrap();
+    let new_head: u64 = output.newHead.try_into().unwrap();
+    // assert the trusted values
+    assert_eq!(previous.height, previous_head);
+    assert_eq!(previous.root, output.prevHeader.to_vec());
+    assert!(previous.height < new_head);
+    TrustedState {
+        height: new_head,
+        root: output.newHeader.to_vec(),
+    }
 }
 
 sol! {
@@ -96,3 +268,166 @@ sol! {
         bytes32 nextSyncCommitteeHash;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::verify_insertions;
+    use types::{DomainTransition, SmtContext, SmtInsertion, SmtKey, TrustedState};
+    use valence_coprocessor_core::MemorySmt;
+
+    /// Inserts a value and builds its witness the way the coprocessor host does
+    fn insert(
+        smt: &MemorySmt,
+        root: [u8; 32],
+        context: &str,
+        key: [u8; 32],
+        data: Vec<u8>,
+    ) -> SmtInsertion {
+        let previous_data = smt
+            .get_opening(context, root, &key)
+            .unwrap()
+            .map(|opening| opening.data);
+        let new_root = smt.insert(root, context, &key, data).unwrap();
+        SmtInsertion {
+            context: context.to_string(),
+            opening: smt.get_opening(context, new_root, &key).unwrap().unwrap(),
+            previous_data,
+            root: new_root,
+        }
+    }
+
+    /// The transition of a domain bootstrapped to `target`
+    fn bootstrap(domain_id: &str, target: TrustedState) -> DomainTransition {
+        DomainTransition {
+            domain_id: domain_id.to_string(),
+            previous: None,
+            anchor: Some(TrustedState::default()),
+            reanchored: false,
+            target,
+            vk: String::new(),
+        }
+    }
+
+    /// Inserts the height and root of a domain
+    fn insert_domain(
+        smt: &MemorySmt,
+        root: [u8; 32],
+        domain_id: &str,
+        state: &TrustedState,
+    ) -> Vec<SmtInsertion> {
+        let context = SmtContext::LightClients.name();
+        let height = insert(
+            smt,
+            root,
+            context,
+            SmtKey::DomainHeight(domain_id).derive(),
+            state.height.to_be_bytes().to_vec(),
+        );
+        let root = insert(
+            smt,
+            height.root,
+            context,
+            SmtKey::DomainRoot(domain_id).derive(),
+            state.root.clone(),
+        );
+        vec![height, root]
+    }
+
+    #[test]
+    fn test_bootstrap_from_empty_root() {
+        let smt = MemorySmt::default();
+        let target = TrustedState {
+            height: 10,
+            root: vec![1; 32],
+        };
+        let insertions = insert_domain(&smt, [0; 32], "neutron", &target);
+        assert!(
+            insertions
+                .iter()
+                .all(|insertion| insertion.previous_data.is_none())
+        );
+        verify_insertions(
+            &[0; 32],
+            &insertions,
+            &insertions[1].root,
+            &[bootstrap("neutron", target)],
+        );
+    }
+
+    #[test]
+    fn test_new_key_next_to_existing_leaf() {
+        let smt = MemorySmt::default();
+        let existing = [0x11; 32];
+        let previous_root = insert(&smt, [0; 32], "mailbox", existing, vec![1]).root;
+        // the new key only differs from the existing one in its last bit
+        let mut key = existing;
+        key[31] ^= 1;
+        let insertion = insert(&smt, previous_root, "mailbox", key, vec![2]);
+        assert_eq!(insertion.previous_data, None);
+        let root = insertion.root;
+        verify_insertions(&previous_root, &[insertion], &root, &[]);
+        // the existing leaf is preserved
+        assert_eq!(
+            smt.get_opening("mailbox", root, &existing)
+                .unwrap()
+                .unwrap()
+                .data,
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_overwrite() {
+        let smt = MemorySmt::default();
+        let key = [0x22; 32];
+        let previous_root = insert(&smt, [0; 32], "mailbox", key, vec![1]).root;
+        let insertion = insert(&smt, previous_root, "mailbox", key, vec![2]);
+        assert_eq!(insertion.previous_data, Some(vec![1]));
+        let root = insertion.root;
+        verify_insertions(&previous_root, &[insertion], &root, &[]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unproven write to the light client context")]
+    fn test_unproven_light_client_write() {
+        let smt = MemorySmt::default();
+        let target = TrustedState {
+            height: 10,
+            root: vec![1; 32],
+        };
+        // the stored height is not the one proven for the domain
+        let written = TrustedState {
+            height: 11,
+            root: target.root.clone(),
+        };
+        let insertions = insert_domain(&smt, [0; 32], "neutron", &written);
+        verify_insertions(
+            &[0; 32],
+            &insertions,
+            &insertions[1].root,
+            &[bootstrap("neutron", target)],
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Bootstrapped domain neutron has state under the previous root")]
+    fn test_bootstrap_over_existing_state() {
+        let smt = MemorySmt::default();
+        let stored = TrustedState {
+            height: 5,
+            root: vec![2; 32],
+        };
+        let previous_root = insert_domain(&smt, [0; 32], "neutron", &stored)[1].root;
+        let target = TrustedState {
+            height: 10,
+            root: vec![1; 32],
+        };
+        let insertions = insert_domain(&smt, previous_root, "neutron", &target);
+        verify_insertions(
+            &previous_root,
+            &insertions,
+            &insertions[1].root,
+            &[bootstrap("neutron", target)],
+        );
+    }
+}
@@ -1,6 +1,7 @@
 /*
     This circuit verifies merkle proofs from different domains and builds a SMT from them.
-    The SMT trie root is committed as a public output.
+    The previous and the new SMT trie root, together with the proven domain heights,
+    are committed as a public output, so that consecutive updates form a chain.
     The new trie root, alongslide with the proof can be sent to the different domains.
 */
 #![no_main]
@@ -12,5 +13,5 @@ pub fn main() {
         .expect("Failed to deserialize MerkleProofInputs");
 
     let circuit_outputs = coprocessor_logic(inputs);
-    sp1_zkvm::io::commit_slice(&circuit_outputs);
+    sp1_zkvm::io::commit_slice(&borsh::to_vec(&circuit_outputs).unwrap());
 }
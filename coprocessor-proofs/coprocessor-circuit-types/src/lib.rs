@@ -1,28 +1,448 @@
+use common_merkle_proofs::merkle::types::MerkleVerifiable;
+pub use coprocessor_smt_keys::{ETHEREUM_DOMAIN_ID, NEUTRON_DOMAIN_ID, SmtContext, SmtKey};
+use ethereum_merkle_proofs::merkle_lib::{keccak::digest_keccak, types::EthereumMerkleProof};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 use valence_coprocessor_core::SmtOpening;
 
+/// The zk light client that proves the state of a domain
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    borsh::BorshSerialize,
+    borsh::BorshDeserialize,
+)]
+pub enum LightClientKind {
+    /// SP1 Tendermint light client for Cosmos SDK chains
+    Tendermint,
+    /// SP1 Helios light client for the Ethereum beacon chain
+    Helios,
+}
+
+/// How the SP1 proof of a light client update is wrapped for verification
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    borsh::BorshSerialize,
+    borsh::BorshDeserialize,
+)]
+pub enum ProofMode {
+    #[default]
+    Groth16,
+    Plonk,
+}
+
+impl FromStr for ProofMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "groth16" => Ok(Self::Groth16),
+            "plonk" => Ok(Self::Plonk),
+            mode => Err(format!(
+                "Invalid proof mode {}, expected groth16 or plonk",
+                mode
+            )),
+        }
+    }
+}
+
+/// A height and header root of a domain, as proven by its light client
+#[derive(
+    Debug,
+    Clone,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    borsh::BorshSerialize,
+    borsh::BorshDeserialize,
+)]
+pub struct TrustedState {
+    /// The block height (or beacon slot for Ethereum)
+    pub height: u64,
+    /// The header hash (or beacon header root for Ethereum) at `height`
+    pub root: Vec<u8>,
+}
+
+/// SMT openings of the height and root stored for a domain
+#[derive(Debug, Clone, Serialize, Deserialize, borsh::BorshSerialize, borsh::BorshDeserialize)]
+pub struct DomainOpenings {
+    /// The domain the openings belong to
+    pub domain_id: String,
+    /// SMT opening containing the height of the domain
+    pub height_opening: SmtOpening,
+    /// SMT opening containing the root of the domain
+    pub root_opening: SmtOpening,
+}
+
+/// Finds the openings of a domain in a list of domain openings
+pub fn find_domain_openings<'a>(
+    openings: &'a [DomainOpenings],
+    domain_id: &str,
+) -> Option<&'a DomainOpenings> {
+    openings
+        .iter()
+        .find(|openings| openings.domain_id == domain_id)
+}
+
+/// Merkle proofs of many storage slots of a single Ethereum account, fetched with one request
+#[derive(Debug, Clone, Serialize, Deserialize, borsh::BorshSerialize, borsh::BorshDeserialize)]
+pub struct EthereumAccountProofs {
+    /// Proof of the account against the execution state root
+    pub account_proof: EthereumMerkleProof,
+    /// The storage root of the account, the third field of the account proof value
+    pub storage_root: Vec<u8>,
+    /// Proofs of the storage slots against `storage_root`, in the order they were requested
+    pub storage_proofs: Vec<EthereumMerkleProof>,
+}
+
+impl EthereumAccountProofs {
+    /// Verifies the account proof against a state root, then the storage proofs against the
+    /// storage root of the proven account
+    ///
+    /// # Arguments
+    /// * `state_root` - The execution state root the account is proven against
+    pub fn verify(&self, state_root: &[u8]) -> Result<(), String> {
+        self.account_proof
+            .verify(state_root)
+            .map_err(|_| "Failed to verify the account proof".to_string())?;
+        // the storage root is only trusted once it matches the proven account leaf
+        let storage_root = decode_account_storage_root(&self.account_proof.value)
+            .ok_or_else(|| "Invalid account proof value".to_string())?;
+        if self.storage_root != storage_root {
+            return Err("Storage root does not match the proven account".to_string());
+        }
+        for storage_proof in &self.storage_proofs {
+            storage_proof
+                .verify(&self.storage_root)
+                .map_err(|_| "Failed to verify a storage proof".to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// The fields an OP-stack output root commits to
+///
+/// The output root is `keccak256(version ++ state_root ++ message_passer_storage_root ++ block_hash)`.
+#[derive(Debug, Clone, Serialize, Deserialize, borsh::BorshSerialize, borsh::BorshDeserialize)]
+pub struct OpStackOutputRootPreimage {
+    /// The output root version, 32 zero bytes for version 0
+    pub version: Vec<u8>,
+    /// The L2 state root the L2 account proofs are verified against
+    pub state_root: Vec<u8>,
+    /// The storage root of the `L2ToL1MessagePasser` predeploy
+    pub message_passer_storage_root: Vec<u8>,
+    /// The hash of the L2 block
+    pub block_hash: Vec<u8>,
+}
+
+impl OpStackOutputRootPreimage {
+    /// Computes the output root committed to L1
+    pub fn output_root(&self) -> [u8; 32] {
+        digest_keccak(
+            &[
+                self.version.as_slice(),
+                &self.state_root,
+                &self.message_passer_storage_root,
+                &self.block_hash,
+            ]
+            .concat(),
+        )
+    }
+}
+
+/// The L1 contract an OP-stack output root is stored in, which decides how the L2 block number
+/// following the output root is laid out
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    borsh::BorshSerialize,
+    borsh::BorshDeserialize,
+)]
+pub enum OutputRootKind {
+    /// An `OutputProposal` of an L2OutputOracle, the block number is packed into the high
+    /// 128 bits after the 128 bit timestamp
+    L2OutputOracle,
+    /// An `OutputRoot` anchor of an AnchorStateRegistry, the block number takes the whole word
+    AnchorStateRegistry,
+}
+
+/// Proofs of OP-stack L2 state against an output root stored on Ethereum
+#[derive(Debug, Clone, Serialize, Deserialize, borsh::BorshSerialize, borsh::BorshDeserialize)]
+pub struct OpStackStorageProofs {
+    /// The contract layout the output root is stored in
+    pub output_root_kind: OutputRootKind,
+    /// The storage slot of the output root, the L2 block number is stored in the next one
+    pub output_root_slot: Vec<u8>,
+    /// Proof of the output root slot and the L2 block number slot of the L2OutputOracle or
+    /// AnchorStateRegistry against the Ethereum execution state root
+    pub output_root_proof: EthereumAccountProofs,
+    /// The fields the proven output root commits to
+    pub output_root_preimage: OpStackOutputRootPreimage,
+    /// The L2 block number the output root was proposed for, proven by `output_root_proof`
+    pub l2_block_number: u64,
+    /// Proofs of L2 accounts and their storage slots against the L2 state root
+    pub account_proofs: Vec<EthereumAccountProofs>,
+}
+
+impl OpStackStorageProofs {
+    /// Verifies the L2 proofs against the output root proven in the Ethereum state
+    ///
+    /// Applications still have to constrain the L1 contract, the output root kind and slot, as
+    /// well as the L2 keys they read.
+    ///
+    /// # Arguments
+    /// * `ethereum_state_root` - The Ethereum execution state root, proven by the Helios domain
+    pub fn verify(&self, ethereum_state_root: &[u8]) -> Result<(), String> {
+        let [output_root_proof, block_number_proof] =
+            self.output_root_proof.storage_proofs.as_slice()
+        else {
+            return Err("Expected output root and L2 block number storage proofs".to_string());
+        };
+        let output_root_slot: [u8; 32] = self
+            .output_root_slot
+            .as_slice()
+            .try_into()
+            .map_err(|_| "Invalid output root slot".to_string())?;
+        let block_number_slot = increment_word(output_root_slot)
+            .ok_or_else(|| "Invalid output root slot".to_string())?;
+        if output_root_proof.key != digest_keccak(&output_root_slot)
+            || block_number_proof.key != digest_keccak(&block_number_slot)
+        {
+            return Err("Storage proofs are not of the output root slots".to_string());
+        }
+        self.output_root_proof
+            .verify(ethereum_state_root)
+            .map_err(|e| format!("Output root proofs: {}", e))?;
+        let output_root = decode_rlp_word(&output_root_proof.value)
+            .ok_or_else(|| "Invalid output root storage value".to_string())?;
+        if output_root != self.output_root_preimage.output_root() {
+            return Err("Output root does not match its preimage".to_string());
+        }
+        let block_number_word = decode_rlp_word(&block_number_proof.value)
+            .ok_or_else(|| "Invalid L2 block number storage value".to_string())?;
+        let block_number = match self.output_root_kind {
+            OutputRootKind::L2OutputOracle => &block_number_word[..16],
+            OutputRootKind::AnchorStateRegistry => &block_number_word[..],
+        };
+        if decode_u64_word(block_number) != Some(self.l2_block_number) {
+            return Err("L2 block number does not match the proven output root".to_string());
+        }
+        for account_proofs in &self.account_proofs {
+            account_proofs
+                .verify(&self.output_root_preimage.state_root)
+                .map_err(|e| format!("L2 proofs: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the storage root, the third field, of an RLP encoded account
+/// `[nonce, balance, storage_root, code_hash]`
+fn decode_account_storage_root(value: &[u8]) -> Option<[u8; 32]> {
+    let (&header, rest) = value.split_first()?;
+    let payload = match header {
+        0xc0..=0xf7 => rest,
+        0xf8..=0xff => rest.get((header - 0xf7) as usize..)?,
+        _ => return None,
+    };
+    let (_nonce, payload) = split_rlp_string(payload)?;
+    let (_balance, payload) = split_rlp_string(payload)?;
+    let (storage_root, _) = split_rlp_string(payload)?;
+    storage_root.try_into().ok()
+}
+
+/// Splits the first RLP string off `bytes`, returning its payload and the remaining bytes
+fn split_rlp_string(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&header, rest) = bytes.split_first()?;
+    let (offset, len) = match header {
+        0x00..=0x7f => return Some((&bytes[..1], rest)),
+        0x80..=0xb7 => (0, (header - 0x80) as usize),
+        0xb8..=0xbf => {
+            let len_bytes = rest.get(..(header - 0xb7) as usize)?;
+            let len = len_bytes.iter().try_fold(0usize, |len, byte| {
+                len.checked_mul(256)?.checked_add(*byte as usize)
+            })?;
+            (len_bytes.len(), len)
+        }
+        _ => return None,
+    };
+    let end = offset.checked_add(len)?;
+    Some((rest.get(offset..end)?, rest.get(end..)?))
+}
+
+/// Decodes an RLP encoded storage value into a left-padded 32 byte word
+fn decode_rlp_word(value: &[u8]) -> Option<[u8; 32]> {
+    let payload = match value {
+        [byte] if *byte < 0x80 => value,
+        [header, payload @ ..] if (0x80..=0xa0).contains(header) => {
+            (payload.len() == (*header - 0x80) as usize).then_some(payload)?
+        }
+        _ => return None,
+    };
+    let mut word = [0u8; 32];
+    word[32 - payload.len()..].copy_from_slice(payload);
+    Some(word)
+}
+
+/// Adds one to a big endian 256 bit word, `None` on overflow
+fn increment_word(mut word: [u8; 32]) -> Option<[u8; 32]> {
+    for byte in word.iter_mut().rev() {
+        let (sum, carry) = byte.overflowing_add(1);
+        *byte = sum;
+        if !carry {
+            return Some(word);
+        }
+    }
+    None
+}
+
+/// Decodes a big endian integer into a `u64`, `None` if it does not fit
+fn decode_u64_word(bytes: &[u8]) -> Option<u64> {
+    let (high, low) = bytes.split_at(bytes.len().checked_sub(8)?);
+    high.iter()
+        .all(|byte| *byte == 0)
+        .then(|| u64::from_be_bytes(low.try_into().unwrap()))
+}
+
+/// A single zk light-client proof
+#[derive(Debug, Clone, borsh::BorshSerialize, borsh::BorshDeserialize)]
+pub struct LightClientProof {
+    /// The proof system the proof is wrapped in
+    pub proof_mode: ProofMode,
+    pub proof: Vec<u8>,
+    pub public_values: Vec<u8>,
+    pub vk: String,
+}
+
+/// A zk light-client update of a single domain
+#[derive(Debug, Clone, borsh::BorshSerialize, borsh::BorshDeserialize)]
+pub struct DomainUpdate {
+    /// The light client that produced the proofs
+    pub kind: LightClientKind,
+    /// The light client proofs, each one starting from the state proven by the one before,
+    /// more than one if the update had to be split into intermediate steps
+    pub proofs: Vec<LightClientProof>,
+    /// The state the light client update starts from
+    pub previous: TrustedState,
+    /// Openings of the height and root stored for the domain under the previous coprocessor
+    /// root, `None` when the domain is bootstrapped, its keys are then proven absent by the
+    /// insert witnesses
+    pub previous_openings: Option<DomainOpenings>,
+    /// Whether the update starts from an approved anchor rather than the stored state, which
+    /// it replaces
+    pub reanchored: bool,
+    /// Openings of the new height and root in the coprocessor SMT
+    pub openings: DomainOpenings,
+}
+
+/// Witness of a single SMT insert, applied on top of the root left by the previous insert
+#[derive(Debug, Clone, borsh::BorshSerialize, borsh::BorshDeserialize)]
+pub struct SmtInsertion {
+    /// The context the value was inserted into
+    pub context: String,
+    /// Opening of the inserted value against the root produced by the insert
+    pub opening: SmtOpening,
+    /// The value stored under the key before the insert, `None` if the key is new, in which case
+    /// the opening is checked against the previous root with the empty leaf
+    pub previous_data: Option<Vec<u8>>,
+    /// The root produced by the insert
+    pub root: [u8; 32],
+}
+
 /// Inputs for the coprocessor circuit that contains merkle proofs from different domains
 /// and their corresponding roots for verification.
 #[derive(Debug, Clone, borsh::BorshSerialize, borsh::BorshDeserialize)]
 pub struct CoprocessorCircuitInputs {
-    pub helios_proof: Vec<u8>,
-    pub helios_public_values: Vec<u8>,
-    pub helios_vk: String,
-    pub neutron_proof: Vec<u8>,
-    pub neutron_public_values: Vec<u8>,
-    pub neutron_vk: String,
-    pub previous_neutron_height: u64,
-    pub previous_ethereum_height: u64,
-    pub previous_neutron_root: Vec<u8>,
-    pub previous_ethereum_root: Vec<u8>,
-    pub neutron_height_opening: SmtOpening,
-    pub ethereum_height_opening: SmtOpening,
-    pub neutron_root_opening: SmtOpening,
-    pub ethereum_root_opening: SmtOpening,
+    /// The coprocessor root committed by the previous update, `[0; 32]` for the first update
+    pub previous_coprocessor_root: [u8; 32],
+    /// Updates of the domains that advanced, the others keep their state under the previous root
+    pub domain_updates: Vec<DomainUpdate>,
+    /// Every insert leading from `previous_coprocessor_root` to `coprocessor_root`, in order
+    pub insertions: Vec<SmtInsertion>,
     pub coprocessor_root: [u8; 32],
 }
 
-/// Outputs from the coprocessor circuit containing the verified roots
-#[derive(Debug, Clone, borsh::BorshSerialize, borsh::BorshDeserialize)]
+/// The transition of a single domain proven by a coprocessor update
+#[derive(Debug, Clone, PartialEq, Eq, borsh::BorshSerialize, borsh::BorshDeserialize)]
+pub struct DomainTransition {
+    pub domain_id: String,
+    /// The state stored under the previous coprocessor root, `None` if the domain had no state
+    /// and was bootstrapped
+    pub previous: Option<TrustedState>,
+    /// The state the light client proofs start from if it is not the previous state, which the
+    /// verifier has to check against an approved trusted header
+    pub anchor: Option<TrustedState>,
+    /// Whether the anchor replaced the previous state of the domain, rather than bootstrapping it
+    pub reanchored: bool,
+    /// The state stored under the new coprocessor root
+    pub target: TrustedState,
+    /// The verification key of the light client program that proved the transition, which the
+    /// verifier has to check against the program of the domain
+    pub vk: String,
+}
+
+/// Outputs from the coprocessor circuit, chaining the new root to the root it extends
+#[derive(Debug, Clone, PartialEq, Eq, borsh::BorshSerialize, borsh::BorshDeserialize)]
 pub struct CoprocessorCircuitOutputs {
+    /// The coprocessor root this update extends
+    pub previous_coprocessor_root: [u8; 32],
+    /// The new coprocessor root
     pub coprocessor_root: [u8; 32],
+    /// The proven transition of every updated domain
+    pub domains: Vec<DomainTransition>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_account_storage_root, decode_u64_word, increment_word};
+
+    #[test]
+    fn test_decode_account_storage_root() {
+        // [nonce 1, balance 0, storage root, code hash], a list longer than 55 bytes
+        let account = [
+            [0xf8, 0x44, 0x01, 0x80, 0xa0].as_slice(),
+            &[0x11; 32],
+            &[0xa0],
+            &[0x22; 32],
+        ]
+        .concat();
+        assert_eq!(decode_account_storage_root(&account), Some([0x11; 32]));
+        // truncated accounts are rejected
+        assert_eq!(decode_account_storage_root(&account[..20]), None);
+    }
+
+    #[test]
+    fn test_block_number_slot() {
+        let mut slot = [0u8; 32];
+        slot[31] = 0xff;
+        let mut next = [0u8; 32];
+        next[30] = 1;
+        assert_eq!(increment_word(slot), Some(next));
+        assert_eq!(increment_word([0xff; 32]), None);
+        // an L2OutputOracle proposal packs the block number above the timestamp
+        let mut proposal = [0u8; 32];
+        proposal[15] = 42;
+        proposal[31] = 7;
+        assert_eq!(decode_u64_word(&proposal[..16]), Some(42));
+        assert_eq!(decode_u64_word(&proposal), None);
+    }
 }
@@ -0,0 +1,119 @@
+//! Keys and contexts of the coprocessor SMT.
+//!
+//! Shared by the coprocessor host, the coprocessor circuit and the application circuits so
+//! that every party derives the same key for the same domain field and opens it in the same
+//! context.
+#![no_std]
+
+use sha2::{Digest, Sha256};
+
+/// Domain id of the Neutron chain
+pub const NEUTRON_DOMAIN_ID: &str = "neutron";
+/// Domain id of the Ethereum chain
+pub const ETHEREUM_DOMAIN_ID: &str = "ethereum";
+
+/// Name of the SMT context reserved for the domain heights and roots proven by the light clients
+pub const LIGHT_CLIENT_CONTEXT: &str = "lightclients";
+
+/// `SmtKey::DomainHeight(NEUTRON_DOMAIN_ID).derive()`
+pub const NEUTRON_HEIGHT_KEY: [u8; 32] = [
+    5, 92, 226, 28, 182, 227, 244, 206, 139, 106, 219, 203, 86, 167, 223, 128, 79, 231, 159, 227,
+    28, 76, 212, 19, 61, 221, 239, 48, 60, 35, 162, 102,
+];
+/// `SmtKey::DomainHeight(ETHEREUM_DOMAIN_ID).derive()`
+pub const ETHEREUM_HEIGHT_KEY: [u8; 32] = [
+    225, 27, 47, 17, 45, 96, 202, 66, 172, 66, 54, 240, 184, 154, 153, 9, 185, 64, 83, 168, 31, 33,
+    96, 209, 59, 84, 151, 70, 51, 237, 68, 17,
+];
+/// `SmtKey::DomainRoot(NEUTRON_DOMAIN_ID).derive()`
+pub const NEUTRON_ROOT_KEY: [u8; 32] = [
+    100, 199, 198, 130, 151, 99, 36, 184, 143, 64, 220, 2, 6, 249, 213, 207, 53, 9, 111, 146, 62,
+    7, 251, 165, 129, 136, 106, 115, 4, 154, 4, 226,
+];
+/// `SmtKey::DomainRoot(ETHEREUM_DOMAIN_ID).derive()`
+pub const ETHEREUM_ROOT_KEY: [u8; 32] = [
+    219, 255, 51, 188, 30, 184, 227, 102, 147, 124, 35, 50, 152, 96, 225, 175, 84, 57, 208, 125,
+    236, 134, 108, 17, 77, 195, 169, 130, 177, 237, 235, 53,
+];
+
+/// A namespace of the coprocessor SMT
+///
+/// The same key holds independent values in different contexts, so every insert, opening and
+/// verification names the context it operates in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtContext<'a> {
+    /// Reserved for the domain heights and roots written by the coprocessor
+    LightClients,
+    /// The namespace of a registered application, named by its application id
+    Application(&'a str),
+}
+
+impl SmtContext<'_> {
+    /// The context string passed to the SMT
+    pub fn name(&self) -> &str {
+        match self {
+            SmtContext::LightClients => LIGHT_CLIENT_CONTEXT,
+            SmtContext::Application(application_id) => application_id,
+        }
+    }
+}
+
+/// A typed key of the coprocessor SMT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtKey<'a> {
+    /// The latest proven height (or beacon slot) of a domain
+    DomainHeight(&'a str),
+    /// The latest proven header root of a domain
+    DomainRoot(&'a str),
+}
+
+impl SmtKey<'_> {
+    /// The domain the key belongs to
+    pub fn domain_id(&self) -> &str {
+        match self {
+            SmtKey::DomainHeight(domain_id) | SmtKey::DomainRoot(domain_id) => domain_id,
+        }
+    }
+
+    /// The name of the domain field the key points to
+    pub fn field(&self) -> &'static str {
+        match self {
+            SmtKey::DomainHeight(_) => "height",
+            SmtKey::DomainRoot(_) => "root",
+        }
+    }
+
+    /// Derives the raw SMT key as `sha256("<domain_id>_<field>")`
+    pub fn derive(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.domain_id().as_bytes());
+        hasher.update(b"_");
+        hasher.update(self.field().as_bytes());
+        hasher.finalize().into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_constant_keys_match_derivation() {
+        assert_eq!(
+            SmtKey::DomainHeight(NEUTRON_DOMAIN_ID).derive(),
+            NEUTRON_HEIGHT_KEY
+        );
+        assert_eq!(
+            SmtKey::DomainHeight(ETHEREUM_DOMAIN_ID).derive(),
+            ETHEREUM_HEIGHT_KEY
+        );
+        assert_eq!(
+            SmtKey::DomainRoot(NEUTRON_DOMAIN_ID).derive(),
+            NEUTRON_ROOT_KEY
+        );
+        assert_eq!(
+            SmtKey::DomainRoot(ETHEREUM_DOMAIN_ID).derive(),
+            ETHEREUM_ROOT_KEY
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! On-disk cache of fetched proofs and headers.
+//!
+//! Proofs and headers at a given height never change once the height is final, so a rerun of a
+//! failed proving job can reuse everything the previous run fetched. Entries are addressed by
+//! the hash of `(chain, height, key)` and stored as JSON files in the cache directory.
+//! Once the directory grows beyond its size limit the least recently used entries are evicted.
+//!
+//! Only heights that cannot be reorged may be cached: Tendermint heights are final once committed
+//! and the Ethereum heights used by the coprocessor are taken from finalized beacon slots.
+
+use std::{
+    env,
+    fs::{self, File},
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use dotenvy::dotenv;
+use serde::{Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
+
+use crate::{error::Result, read_coprocessor_data_dir};
+
+/// Default size limit of the cache in megabytes
+const DEFAULT_MAX_MB: u64 = 256;
+
+/// A content-addressed cache of values that are immutable by height
+#[derive(Debug, Clone)]
+pub struct ProofCache {
+    /// The directory holding the entries, `None` if caching is disabled
+    dir: Option<PathBuf>,
+    /// Size limit of all entries in bytes
+    max_bytes: u64,
+}
+
+impl ProofCache {
+    /// Creates a cache in `dir` holding at most `max_bytes`, a limit of 0 disables the cache
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        Self {
+            dir: (max_bytes > 0).then(|| dir.into()),
+            max_bytes,
+        }
+    }
+
+    /// Creates the cache in the `cache` directory of the coprocessor data directory
+    ///
+    /// The size limit is read from `PROOF_CACHE_MAX_MB`, 256 MB if unset.
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        let max_mb = env::var("PROOF_CACHE_MAX_MB")
+            .map(|value| value.parse().expect("Invalid PROOF_CACHE_MAX_MB!"))
+            .unwrap_or(DEFAULT_MAX_MB);
+        Self::new(
+            Path::new(&read_coprocessor_data_dir()).join("cache"),
+            max_mb * 1024 * 1024,
+        )
+    }
+
+    /// Returns the cached value of `key` at `height`, or fetches and caches it
+    ///
+    /// Failing to read or write the cache is not an error, the value is fetched instead.
+    ///
+    /// # Arguments
+    /// * `chain` - The chain the value belongs to
+    /// * `height` - The height the value was fetched at
+    /// * `key` - Identifies the value at `height`
+    /// * `fetch` - Fetches the value on a cache miss
+    pub async fn get_or_fetch<T, F, Fut>(
+        &self,
+        chain: &str,
+        height: u64,
+        key: &[u8],
+        fetch: F,
+    ) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let Some(dir) = &self.dir else {
+            return fetch().await;
+        };
+        let path = dir.join(entry_name(chain, height, key));
+        if let Some(value) = read_entry(&path) {
+            return Ok(value);
+        }
+        let value = fetch().await?;
+        if let Err(e) = self.write_entry(dir, &path, &value) {
+            eprintln!(
+                "Failed to cache {} value at height {}: {}",
+                chain, height, e
+            );
+        }
+        Ok(value)
+    }
+
+    /// Writes an entry and evicts the least recently used entries beyond the size limit
+    fn write_entry<T: Serialize>(&self, dir: &Path, path: &Path, value: &T) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let bytes = serde_json::to_vec(value).map_err(io::Error::other)?;
+        // write to a temporary file first so that readers never see a partial entry
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, bytes)?;
+        fs::rename(&temporary, path)?;
+        self.evict(dir)
+    }
+
+    /// Removes the least recently used entries until the cache fits its size limit
+    fn evict(&self, dir: &Path) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            // entries evicted concurrently are skipped
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            entries.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if size <= self.max_bytes {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => size -= len,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => size -= len,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads an entry, marking it as recently used
+fn read_entry<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let bytes = fs::read(path).ok()?;
+    let value = serde_json::from_slice(&bytes).ok()?;
+    if let Ok(file) = File::options().write(true).open(path) {
+        file.set_modified(SystemTime::now()).ok();
+    }
+    Some(value)
+}
+
+/// The file name of the entry of `key` at `height`
+fn entry_name(chain: &str, height: u64, key: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update((chain.len() as u64).to_be_bytes());
+    hasher.update(chain);
+    hasher.update(height.to_be_bytes());
+    hasher.update(key);
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ProofCache, entry_name};
+    use crate::error::CoprocessorError;
+    use std::{
+        fs,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    #[tokio::test]
+    async fn test_cache_hits_and_eviction() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        // room for two entries of about 200 bytes each
+        let cache = ProofCache::new(cache_dir.path(), 500);
+        let fetches = AtomicUsize::new(0);
+        let fetch = |value: u8| {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            async move { Ok::<_, CoprocessorError>(vec![value; 100]) }
+        };
+
+        let value: Vec<u8> = cache
+            .get_or_fetch("neutron", 1, b"a", || fetch(1))
+            .await
+            .unwrap();
+        assert_eq!(value, vec![1; 100]);
+        let value: Vec<u8> = cache
+            .get_or_fetch("neutron", 1, b"a", || fetch(2))
+            .await
+            .unwrap();
+        assert_eq!(value, vec![1; 100]);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        // the same key at another height or on another chain is a different entry
+        let _: Vec<u8> = cache
+            .get_or_fetch("neutron", 2, b"a", || fetch(3))
+            .await
+            .unwrap();
+        let _: Vec<u8> = cache
+            .get_or_fetch("ethereum", 1, b"a", || fetch(4))
+            .await
+            .unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        // the first entry was the least recently used one
+        assert!(
+            !cache_dir
+                .path()
+                .join(entry_name("neutron", 1, b"a"))
+                .exists()
+        );
+        assert_eq!(fs::read_dir(cache_dir.path()).unwrap().count(), 2);
+
+        // a disabled cache always fetches
+        let cache = ProofCache::new(cache_dir.path(), 0);
+        let value: Vec<u8> = cache
+            .get_or_fetch("ethereum", 1, b"a", || fetch(5))
+            .await
+            .unwrap();
+        assert_eq!(value, vec![5; 100]);
+    }
+}
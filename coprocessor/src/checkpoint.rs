@@ -0,0 +1,199 @@
+//! Checkpointing of the trusted light-client state.
+//!
+//! After every successful coprocessor update the trusted heights and roots are written to a
+//! versioned checkpoint, so that the next run continues from the last proven state. The
+//! checkpoint also keeps the time of every trusted header and the history of re-anchored domains.
+//!
+//! The checkpoint is stored in the SMT store and written in the same transaction as the root it
+//! belongs to, so that a crash can never leave a committed root without its checkpoint.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Result, anyhow};
+use coprocessor_circuit_types::TrustedState;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    coprocessor::Coprocessor,
+    domains::{DomainRegistry, Reanchor},
+    storage::PersistentSmt,
+};
+
+/// The checkpoint format version written by this build
+pub const CHECKPOINT_VERSION: u32 = 2;
+
+/// Trusted light-client state and SMT root after the last proven update
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Format version, bumped on incompatible changes
+    pub version: u32,
+    /// Last proven state of every registered domain, keyed by domain id
+    pub domains: BTreeMap<String, DomainCheckpoint>,
+    /// The coprocessor SMT root committed with this update
+    pub smt_root: [u8; 32],
+}
+
+/// The last proven state of a single domain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DomainCheckpoint {
+    #[serde(flatten)]
+    pub trusted: TrustedState,
+    /// Unix time in seconds of the trusted header, `None` if unknown or if the light client
+    /// has no trusting period
+    #[serde(default)]
+    pub trusted_time: Option<u64>,
+    /// Every re-anchor of the domain, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reanchors: Vec<Reanchor>,
+}
+
+impl Checkpoint {
+    /// Captures the trusted state of the domains committed together with `smt_root`
+    pub fn new(domains: &DomainRegistry, smt_root: [u8; 32]) -> Self {
+        Self {
+            version: CHECKPOINT_VERSION,
+            domains: domains
+                .iter()
+                .map(|domain| {
+                    (
+                        domain.id.clone(),
+                        DomainCheckpoint {
+                            trusted: domain.trusted.clone(),
+                            trusted_time: domain.trusted_time,
+                            reanchors: domain.reanchors.clone(),
+                        },
+                    )
+                })
+                .collect(),
+            smt_root,
+        }
+    }
+
+    /// Restores the trusted state of a coprocessor from this checkpoint
+    ///
+    /// Domains without an entry in the checkpoint keep their current trusted state.
+    /// Fails if the checkpoint does not belong to the root the SMT store was reopened at.
+    pub fn apply(&self, coprocessor: &mut Coprocessor) -> Result<()> {
+        if self.smt_root != coprocessor.smt_tree.committed_root() {
+            return Err(anyhow!(
+                "Checkpoint root {} does not match the committed SMT root {}",
+                hex::encode(self.smt_root),
+                hex::encode(coprocessor.smt_tree.committed_root())
+            ));
+        }
+        for domain in coprocessor.domains.iter_mut() {
+            if let Some(checkpoint) = self.domains.get(&domain.id) {
+                domain.trusted = checkpoint.trusted.clone();
+                domain.trusted_time = checkpoint.trusted_time;
+                domain.reanchors = checkpoint.reanchors.clone();
+            }
+        }
+        coprocessor.smt_root = self.smt_root;
+        Ok(())
+    }
+
+    /// Loads the checkpoint committed together with the last root of the SMT store
+    ///
+    /// # Returns
+    /// `None` if nothing has been committed yet
+    pub fn load(smt_tree: &PersistentSmt) -> Result<Option<Self>> {
+        smt_tree
+            .checkpoint()?
+            .map(|bytes| Self::decode(&bytes))
+            .transpose()
+    }
+
+    /// Decodes a checkpoint, failing if it was written in another format version
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let checkpoint: Self = serde_json::from_slice(bytes)?;
+        if checkpoint.version != CHECKPOINT_VERSION {
+            return Err(anyhow!(
+                "Unsupported checkpoint version {}, expected {}",
+                checkpoint.version,
+                CHECKPOINT_VERSION
+            ));
+        }
+        Ok(checkpoint)
+    }
+
+    /// Encodes the checkpoint to be committed with its root
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CHECKPOINT_VERSION, Checkpoint, DomainCheckpoint};
+    use crate::{domains::Reanchor, storage::PersistentSmt};
+    use coprocessor_circuit_types::TrustedState;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_checkpoint_roundtrip() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let mut smt = PersistentSmt::open(data_dir.path()).unwrap();
+        assert_eq!(Checkpoint::load(&smt).unwrap(), None);
+        let checkpoint = Checkpoint {
+            version: CHECKPOINT_VERSION,
+            domains: [
+                (
+                    "neutron".to_string(),
+                    DomainCheckpoint {
+                        trusted: TrustedState {
+                            height: 100,
+                            root: vec![1; 32],
+                        },
+                        trusted_time: Some(1_700_000_000),
+                        reanchors: vec![Reanchor {
+                            replaced: TrustedState {
+                                height: 10,
+                                root: vec![4; 32],
+                            },
+                            anchor: TrustedState {
+                                height: 90,
+                                root: vec![5; 32],
+                            },
+                            anchor_time: 1_699_999_000,
+                            approval: "proposal 42".to_string(),
+                            applied_at: 1_700_000_100,
+                        }],
+                    },
+                ),
+                (
+                    "ethereum".to_string(),
+                    DomainCheckpoint {
+                        trusted: TrustedState {
+                            height: 200,
+                            root: vec![2; 32],
+                        },
+                        trusted_time: None,
+                        reanchors: Vec::new(),
+                    },
+                ),
+            ]
+            .into(),
+            smt_root: [3; 32],
+        };
+        smt.commit([3; 32], BTreeMap::new(), &checkpoint.encode().unwrap())
+            .unwrap();
+        drop(smt);
+        let smt = PersistentSmt::open(data_dir.path()).unwrap();
+        assert_eq!(Checkpoint::load(&smt).unwrap(), Some(checkpoint.clone()));
+
+        // checkpoints written before header times were tracked still load
+        let legacy = format!(
+            r#"{{"version":{},"domains":{{"neutron":{{"height":100,"root":[1]}}}},"smt_root":{:?}}}"#,
+            CHECKPOINT_VERSION, [3u8; 32]
+        );
+        let loaded = Checkpoint::decode(legacy.as_bytes()).unwrap();
+        assert_eq!(loaded.domains["neutron"].trusted.height, 100);
+        assert_eq!(loaded.domains["neutron"].trusted_time, None);
+
+        let outdated = Checkpoint {
+            version: CHECKPOINT_VERSION + 1,
+            ..checkpoint
+        };
+        assert!(Checkpoint::decode(&outdated.encode().unwrap()).is_err());
+    }
+}
@@ -1,12 +1,41 @@
 //! Client implementations for supported domains.
 //!
 //! This module provides client implementations for interacting with different blockchain networks
-//! in a zero-knowledge context. Currently supports Neutron and Ethereum networks.
+//! in a zero-knowledge context. Currently supports any number of Tendermint (Cosmos SDK) chains,
+//! such as Neutron, Ethereum and any number of OP-stack L2s.
 
-use alloy::providers::{Provider, ProviderBuilder};
-use base64::Engine;
-use std::str::FromStr;
-use tendermint_rpc::{Client, Url as TendermintUrl};
+use alloy::{
+    eips::{BlockId, BlockNumberOrTag},
+    primitives::{Address, U256},
+    providers::{Provider, ProviderBuilder, RootProvider, WsConnect},
+};
+use anyhow::anyhow;
+use beacon::get_electra_block;
+use dotenvy::dotenv;
+use futures::{
+    FutureExt, StreamExt,
+    future::ready,
+    stream::{self, BoxStream},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{env, str::FromStr};
+use tendermint::{Time, block::Height};
+use tendermint_light_client_verifier::types::{LightBlock, PeerId, ValidatorSet};
+use tendermint_rpc::{
+    Client, HttpClient, Paging, SubscriptionClient, Url as TendermintUrl, WebSocketClient,
+    query::EventType,
+};
+
+use crate::{
+    cache::ProofCache,
+    domains::{
+        OpStackChainConfig, TendermintChainConfig, read_op_stack_domains, read_tendermint_domains,
+    },
+    error::{CoprocessorError, Result},
+    heads::{HeightStream, finalized_heights, read_head_poll_interval},
+    read_ethereum_consensus_rpc_url, read_ethereum_rpc_urls, read_ethereum_ws_url,
+    transport::{RetryConfig, Transport},
+};
 
 /// Trait defining the interface for blockchain clients
 ///
@@ -20,86 +49,686 @@ pub trait ClientInterface {
     /// A tuple containing:
     /// - The state root as a byte vector
     /// - The block height as a u64
-    async fn get_latest_root_and_height(&self) -> (Vec<u8>, u64);
+    async fn get_latest_root_and_height(&self) -> Result<(Vec<u8>, u64)>;
+
+    /// Streams the heights of newly finalized blocks, starting with the current height
+    ///
+    /// New blocks are announced by a websocket subscription if one is configured for the chain,
+    /// and polled otherwise.
+    fn finalized_heights(&self) -> HeightStream;
+}
+
+/// Turns a websocket subscription that may fail to connect into a stream of new-head
+/// notifications, where a failed connection ends the stream with its error
+fn new_head_notifications(
+    subscription: impl Future<Output = Result<BoxStream<'static, Result<()>>>> + Send + 'static,
+) -> BoxStream<'static, Result<()>> {
+    stream::once(subscription)
+        .flat_map(|subscription| {
+            subscription.unwrap_or_else(|e| stream::once(ready(Err(e))).boxed())
+        })
+        .boxed()
+}
+
+/// An app hash together with the heights it relates to
+///
+/// Tendermint headers carry the app hash of the state *before* the block was executed, so the
+/// app hash found in the header at height `H` commits to the application state after `H - 1`.
+/// Storage proofs against this app hash must be requested at `state_height`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttestedAppHash {
+    /// The app hash
+    pub app_hash: Vec<u8>,
+    /// Height of the header that carries the app hash
+    pub header_height: u64,
+    /// Height of the application state the app hash commits to, `header_height - 1`
+    pub state_height: u64,
+}
+
+/// The header fields of a Tendermint block at a given height
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TendermintState {
+    /// The block height
+    pub height: u64,
+    /// Hash of the header, the root tracked by the Tendermint light client
+    pub header_hash: Vec<u8>,
+    /// Hash of the validator set that signed the header
+    pub validators_hash: Vec<u8>,
+    /// Block time of the header
+    pub time: Time,
+    /// The app hash carried by the header, attesting to the state at `height - 1`
+    pub app_hash: AttestedAppHash,
 }
 
-/// Client implementation for interacting with the Neutron blockchain
+/// A connection to a single Tendermint RPC endpoint
 #[derive(Debug, Clone)]
-pub struct NeutronClient {
-    /// The RPC URL endpoint for connecting to the Neutron node
+pub struct TendermintConnection {
+    /// The RPC URL of the endpoint
     pub rpc_url: String,
+    /// The pooled Tendermint RPC client of the endpoint
+    pub http_client: HttpClient,
+}
+
+impl TendermintConnection {
+    fn connect(rpc_url: &str) -> Result<Self> {
+        let url = TendermintUrl::from_str(rpc_url)
+            .map_err(|e| CoprocessorError::Config(format!("Invalid Tendermint RPC url: {}", e)))?;
+        Ok(Self {
+            rpc_url: rpc_url.to_string(),
+            http_client: HttpClient::new(url).map_err(|e| {
+                CoprocessorError::Config(format!("Failed to create Tendermint RPC client: {}", e))
+            })?,
+        })
+    }
+}
+
+/// Client implementation for interacting with a Tendermint (Cosmos SDK) chain
+///
+/// Cheap to clone, clones share the connections to the RPC endpoints of the chain.
+#[derive(Debug, Clone)]
+pub struct TendermintClient {
+    /// The configuration of the chain
+    pub config: TendermintChainConfig,
+    /// The connections to the RPC endpoints of the chain
+    pub transport: Transport<TendermintConnection>,
+    /// Cache of the headers and storage proofs fetched at final heights
+    pub cache: ProofCache,
 }
 
-impl ClientInterface for NeutronClient {
-    async fn get_latest_root_and_height(&self) -> (Vec<u8>, u64) {
-        let tendermint_client =
-            tendermint_rpc::HttpClient::new(TendermintUrl::from_str(&self.rpc_url).unwrap())
-                .unwrap();
-        let latest_block = tendermint_client.latest_block().await.unwrap();
+impl ClientInterface for TendermintClient {
+    async fn get_latest_root_and_height(&self) -> Result<(Vec<u8>, u64)> {
+        let context = format!("{} latest block", self.config.domain_id);
+        let latest_block = self
+            .transport
+            .call(&context, |connection| {
+                let context = &context;
+                async move {
+                    connection
+                        .http_client
+                        .latest_block()
+                        .await
+                        .map_err(|e| CoprocessorError::rpc(context, e))
+                }
+            })
+            .await?;
+        self.check_chain_id(&latest_block.block.header)?;
         let height = latest_block.block.header.height.value() - 1;
-        let app_hash = base64::engine::general_purpose::STANDARD
-            .encode(hex::decode(latest_block.block.header.app_hash.to_string()).unwrap());
-        (
-            base64::engine::general_purpose::STANDARD
-                .decode(app_hash)
-                .unwrap(),
+        Ok((
+            latest_block.block.header.app_hash.as_bytes().to_vec(),
             height,
-        )
+        ))
     }
+
+    fn finalized_heights(&self) -> HeightStream {
+        let client = self.clone();
+        let notifications = self.config.ws_url.clone().map(|ws_url| {
+            new_head_notifications(subscribe_tendermint_blocks(
+                self.config.domain_id.clone(),
+                ws_url,
+            ))
+        });
+        finalized_heights(notifications, read_head_poll_interval(), move || {
+            let client = client.clone();
+            async move { Ok(client.get_latest_root_and_height().await?.1) }.boxed()
+        })
+    }
+}
+
+/// Subscribes to the new blocks of a Tendermint websocket endpoint
+async fn subscribe_tendermint_blocks(
+    domain_id: String,
+    ws_url: String,
+) -> Result<BoxStream<'static, Result<()>>> {
+    let context = format!("{} new block subscription", domain_id);
+    let url = TendermintUrl::from_str(&ws_url).map_err(|e| {
+        CoprocessorError::Config(format!("Invalid {} websocket url: {}", domain_id, e))
+    })?;
+    let (client, driver) = WebSocketClient::new(url)
+        .await
+        .map_err(|e| CoprocessorError::rpc(format!("{} websocket", domain_id), e))?;
+    tokio::spawn(driver.run());
+    let subscription = client
+        .subscribe(EventType::NewBlock.into())
+        .await
+        .map_err(|e| CoprocessorError::rpc(&context, e))?;
+    Ok(subscription
+        .map(move |event| {
+            // the client is owned by the stream, dropping it would close the subscription
+            let _client = &client;
+            event
+                .map(|_| ())
+                .map_err(|e| CoprocessorError::rpc(&context, e))
+        })
+        .boxed())
 }
 
-impl NeutronClient {
-    pub async fn get_state_at_height(&self, height: u64) -> (Vec<u8>, u64) {
-        let tendermint_client =
-            tendermint_rpc::HttpClient::new(TendermintUrl::from_str(&self.rpc_url).unwrap())
-                .unwrap();
-        let latest_block = tendermint_client.latest_block().await.unwrap();
-        let app_hash = base64::engine::general_purpose::STANDARD
-            .encode(hex::decode(latest_block.block.header.app_hash.to_string()).unwrap());
-        (
-            base64::engine::general_purpose::STANDARD
-                .decode(app_hash)
-                .unwrap(),
+impl TendermintClient {
+    /// Connects to the RPC endpoints of a chain
+    pub fn connect(config: TendermintChainConfig) -> Result<Self> {
+        Ok(Self {
+            transport: Transport::connect(
+                config.rpc_urls.clone(),
+                RetryConfig::from_env(),
+                TendermintConnection::connect,
+            )?,
+            config,
+            cache: ProofCache::from_env(),
+        })
+    }
+
+    /// Connects to the chain of `domain_id`, configured in the environment
+    pub fn from_env(domain_id: &str) -> Result<Self> {
+        Self::connect(TendermintChainConfig::from_env(domain_id)?)
+    }
+
+    /// Queries the header fields of the block at `height`
+    ///
+    /// # Arguments
+    /// * `height` - The block height to query
+    ///
+    /// # Returns
+    /// The header hash, validators hash and time at `height`, together with the app hash of
+    /// the header, which attests to the state at `height - 1`
+    pub async fn get_state_at_height(&self, height: u64) -> Result<TendermintState> {
+        let header = self.get_header_at_height(height).await?;
+        if header.height.value() != height {
+            return Err(CoprocessorError::rpc(
+                format!("{} block {}", self.config.domain_id, height),
+                anyhow!("Node returned the header at height {}", header.height),
+            ));
+        }
+        Ok(TendermintState {
             height,
-        )
+            header_hash: header.hash().as_bytes().to_vec(),
+            validators_hash: header.validators_hash.as_bytes().to_vec(),
+            time: header.time,
+            app_hash: AttestedAppHash {
+                app_hash: header.app_hash.as_bytes().to_vec(),
+                header_height: height,
+                state_height: height.saturating_sub(1),
+            },
+        })
+    }
+
+    /// Queries the header of the block at `height`, served from the cache if it was fetched before
+    ///
+    /// # Errors
+    /// Fails if the header belongs to another chain than the configured one
+    pub async fn get_header_at_height(
+        &self,
+        height: u64,
+    ) -> Result<tendermint::block::header::Header> {
+        let header: tendermint::block::header::Header = self
+            .cache
+            .get_or_fetch(&self.config.domain_id, height, b"header", || async {
+                let height = Height::try_from(height).map_err(|e| {
+                    CoprocessorError::decoding(format!("{} height", self.config.domain_id), e)
+                })?;
+                let context = format!("{} block {}", self.config.domain_id, height);
+                let block = self
+                    .transport
+                    .call(&context, |connection| {
+                        let context = &context;
+                        async move {
+                            connection
+                                .http_client
+                                .block(height)
+                                .await
+                                .map_err(|e| CoprocessorError::rpc(context, e))
+                        }
+                    })
+                    .await?;
+                Ok(block.block.header)
+            })
+            .await?;
+        self.check_chain_id(&header)?;
+        Ok(header)
+    }
+
+    /// Queries the light block at `height`, served from the cache if it was fetched before
+    ///
+    /// The signed header and the validator sets of `height` and `height + 1` are fetched through
+    /// the transport, so failing endpoints are retried and skipped like for any other request.
+    ///
+    /// # Errors
+    /// Fails if the light block belongs to another chain than the configured one
+    pub async fn get_light_block(&self, height: u64) -> Result<LightBlock> {
+        let light_block: LightBlock = self
+            .cache
+            .get_or_fetch(&self.config.domain_id, height, b"light_block", || async {
+                let height = Height::try_from(height).map_err(|e| {
+                    CoprocessorError::decoding(format!("{} height", self.config.domain_id), e)
+                })?;
+                let context = format!("{} light block {}", self.config.domain_id, height);
+                let (commit, validators, next_validators) = self
+                    .transport
+                    .call(&context, |connection| {
+                        let context = &context;
+                        async move {
+                            let client = &connection.http_client;
+                            let commit = client
+                                .commit(height)
+                                .await
+                                .map_err(|e| CoprocessorError::rpc(context, e))?;
+                            let validators = client
+                                .validators(height, Paging::All)
+                                .await
+                                .map_err(|e| CoprocessorError::rpc(context, e))?;
+                            let next_validators = client
+                                .validators(height.increment(), Paging::All)
+                                .await
+                                .map_err(|e| CoprocessorError::rpc(context, e))?;
+                            Ok((commit, validators, next_validators))
+                        }
+                    })
+                    .await?;
+                Ok(LightBlock::new(
+                    convert_light_block_part(&commit.signed_header)?,
+                    ValidatorSet::without_proposer(convert_light_block_part(
+                        &validators.validators,
+                    )?),
+                    ValidatorSet::without_proposer(convert_light_block_part(
+                        &next_validators.validators,
+                    )?),
+                    // the provider is not part of what the light client verifies
+                    PeerId::new([0; 20]),
+                ))
+            })
+            .await?;
+        let chain_id = &light_block.signed_header.header.chain_id;
+        if chain_id.as_str() != self.config.chain_id {
+            return Err(CoprocessorError::Config(format!(
+                "The {} RPC serves chain {}, expected {}",
+                self.config.domain_id, chain_id, self.config.chain_id
+            )));
+        }
+        Ok(light_block)
+    }
+
+    /// Rejects headers of other chains, e.g. from an RPC endpoint that was misconfigured
+    fn check_chain_id(&self, header: &tendermint::block::header::Header) -> Result<()> {
+        if header.chain_id.as_str() != self.config.chain_id {
+            return Err(CoprocessorError::Config(format!(
+                "The {} RPC serves chain {}, expected {}",
+                self.config.domain_id, header.chain_id, self.config.chain_id
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Converts a light block part returned by the RPC client into the type of the light client
+/// verifier, which depends on a newer tendermint version with the same JSON encoding
+fn convert_light_block_part<T: Serialize, U: DeserializeOwned>(part: &T) -> Result<U> {
+    serde_json::to_value(part)
+        .and_then(serde_json::from_value)
+        .map_err(|e| CoprocessorError::decoding("Tendermint light block", e))
+}
+
+/// A connection to a single Ethereum RPC endpoint
+#[derive(Debug, Clone)]
+pub struct EthereumConnection {
+    /// The RPC URL of the endpoint
+    pub rpc_url: String,
+    /// The pooled alloy provider of the endpoint
+    pub provider: RootProvider,
+}
+
+impl EthereumConnection {
+    fn connect(rpc_url: &str) -> Result<Self> {
+        let url = url::Url::from_str(rpc_url)
+            .map_err(|e| CoprocessorError::Config(format!("Invalid Ethereum RPC url: {}", e)))?;
+        Ok(Self {
+            rpc_url: rpc_url.to_string(),
+            provider: RootProvider::new_http(url),
+        })
     }
-    pub async fn get_header_at_height(&self, height: u64) -> tendermint::block::header::Header {
-        let tendermint_client =
-            tendermint_rpc::HttpClient::new(TendermintUrl::from_str(&self.rpc_url).unwrap())
-                .unwrap();
-        let header = tendermint_client.block(height as u32).await.unwrap();
-        header.block.header
+}
+
+/// The Ethereum execution block a query is made against
+///
+/// Only finalized blocks can never be reorged away, and they are the blocks the Helios light
+/// client proves.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlockSelection {
+    /// The latest finalized block
+    #[default]
+    Finalized,
+    /// The latest safe block, which is unlikely but not impossible to be reorged away
+    Safe,
+    /// The block at a specific height
+    Number(u64),
+}
+
+impl BlockSelection {
+    /// Reads the block selection from `ETHEREUM_BLOCK_SELECTION`, finalized if unset
+    pub fn from_env() -> Result<Self> {
+        dotenv().ok();
+        env::var("ETHEREUM_BLOCK_SELECTION")
+            .map(|selection| selection.parse())
+            .unwrap_or(Ok(Self::default()))
     }
 }
 
+impl FromStr for BlockSelection {
+    type Err = CoprocessorError;
+
+    fn from_str(selection: &str) -> Result<Self> {
+        match selection {
+            "finalized" => Ok(Self::Finalized),
+            "safe" => Ok(Self::Safe),
+            number => number.parse().map(Self::Number).map_err(|_| {
+                CoprocessorError::Config(format!(
+                    "Invalid Ethereum block selection {}, expected finalized, safe or a number",
+                    selection
+                ))
+            }),
+        }
+    }
+}
+
+impl From<BlockSelection> for BlockNumberOrTag {
+    fn from(selection: BlockSelection) -> Self {
+        match selection {
+            BlockSelection::Finalized => Self::Finalized,
+            BlockSelection::Safe => Self::Safe,
+            BlockSelection::Number(number) => Self::Number(number),
+        }
+    }
+}
+
+/// The header fields of an Ethereum execution block
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExecutionBlock {
+    /// The block number
+    pub number: u64,
+    /// The block hash
+    pub hash: Vec<u8>,
+    /// The state root storage proofs are verified against
+    pub state_root: Vec<u8>,
+}
+
 /// Client implementation for interacting with the Ethereum blockchain
+///
+/// Cheap to clone, clones share the connections to the Ethereum endpoints.
 #[derive(Debug, Clone)]
 pub struct EthereumClient {
-    /// The RPC URL endpoint for connecting to the Ethereum node
-    pub rpc_url: String,
+    /// The connections to the Ethereum RPC endpoints
+    pub transport: Transport<EthereumConnection>,
+    /// The beacon node urls
+    pub beacon: Transport,
+    /// The block `get_latest_root_and_height` reports
+    pub block_selection: BlockSelection,
+    /// Cache of the proofs fetched at finalized heights
+    pub cache: ProofCache,
 }
 
-impl ClientInterface for EthereumClient {
-    async fn get_latest_root_and_height(&self) -> (Vec<u8>, u64) {
-        let provider = ProviderBuilder::new().on_http(url::Url::from_str(&self.rpc_url).unwrap());
-        let block = provider
-            .get_block_by_number(alloy::eips::BlockNumberOrTag::Latest)
+impl EthereumClient {
+    /// Connects to the Ethereum RPC and beacon endpoints configured in the environment
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            transport: Transport::connect(
+                read_ethereum_rpc_urls(),
+                RetryConfig::from_env(),
+                EthereumConnection::connect,
+            )?,
+            beacon: Transport::new(
+                vec![read_ethereum_consensus_rpc_url()],
+                RetryConfig::from_env(),
+            )?,
+            block_selection: BlockSelection::from_env()?,
+            cache: ProofCache::from_env(),
+        })
+    }
+
+    /// Queries the header fields of the selected execution block
+    pub async fn get_block(&self, selection: BlockSelection) -> Result<ExecutionBlock> {
+        get_execution_block(&self.transport, "Ethereum", selection).await
+    }
+
+    /// Reads a storage slot of a contract at an execution block height
+    ///
+    /// The value is not proven, callers prove it with a storage proof where it matters.
+    pub async fn get_storage_at(&self, address: Address, slot: U256, height: u64) -> Result<U256> {
+        let context = format!("Ethereum storage slot {} of {}", slot, address);
+        self.transport
+            .call(&context, |connection| {
+                let context = &context;
+                async move {
+                    connection
+                        .provider
+                        .get_storage_at(address, slot)
+                        .block_id(BlockId::number(height))
+                        .await
+                        .map_err(|e| CoprocessorError::rpc(context, e))
+                }
+            })
             .await
-            .unwrap()
-            .expect("Failed to get Block!");
-        let ethereum_root = block.header.state_root.to_vec();
-        (ethereum_root, block.header.number)
+    }
+
+    /// Looks up the execution block included in the beacon block at `slot`
+    ///
+    /// The slots proven by the Helios light client are finalized, so the lookup is served from
+    /// the cache once it was made.
+    ///
+    /// # Arguments
+    /// * `slot` - The beacon slot, usually one proven by the Helios light client
+    ///
+    /// # Returns
+    /// The execution block whose state root the beacon block at `slot` commits to
+    ///
+    /// # Errors
+    /// Fails if the beacon block at `slot` is not an Electra block
+    pub async fn get_execution_block_at_slot(&self, slot: u64) -> Result<ExecutionBlock> {
+        self.cache
+            .get_or_fetch("beacon", slot, b"execution_block", || async {
+                let block = self
+                    .beacon
+                    .call("Electra block", |beacon_url| async move {
+                        Ok(get_electra_block(slot, &beacon_url).await?)
+                    })
+                    .await?;
+                let payload = block.message.body.execution_payload.execution_payload;
+                Ok(ExecutionBlock {
+                    number: payload.block_number,
+                    hash: payload.block_hash.into_root().to_vec(),
+                    state_root: payload.state_root.to_vec(),
+                })
+            })
+            .await
+    }
+}
+
+impl ClientInterface for EthereumClient {
+    async fn get_latest_root_and_height(&self) -> Result<(Vec<u8>, u64)> {
+        let block = self.get_block(self.block_selection).await?;
+        Ok((block.state_root, block.number))
+    }
+
+    /// Every new head triggers a query of the selected block, since Ethereum nodes only announce
+    /// the latest block and blocks are finalized an epoch at a time
+    fn finalized_heights(&self) -> HeightStream {
+        let client = self.clone();
+        let notifications = read_ethereum_ws_url()
+            .map(|ws_url| new_head_notifications(subscribe_ethereum_heads(ws_url)));
+        finalized_heights(notifications, read_head_poll_interval(), move || {
+            let client = client.clone();
+            async move { Ok(client.get_latest_root_and_height().await?.1) }.boxed()
+        })
     }
 }
 
-/// A composite client that combines both Neutron and Ethereum clients
+/// Queries the header fields of the selected block of an EVM chain
+async fn get_execution_block(
+    transport: &Transport<EthereumConnection>,
+    chain: &str,
+    selection: BlockSelection,
+) -> Result<ExecutionBlock> {
+    let context = format!("{} {:?} block", chain, selection);
+    let block = transport
+        .call(&context, |connection| {
+            let context = &context;
+            async move {
+                connection
+                    .provider
+                    .get_block_by_number(selection.into())
+                    .await
+                    .map_err(|e| CoprocessorError::rpc(context, e))?
+                    .ok_or_else(|| CoprocessorError::rpc(context, anyhow!("Block not found")))
+            }
+        })
+        .await?;
+    Ok(ExecutionBlock {
+        number: block.header.number,
+        hash: block.header.hash.to_vec(),
+        state_root: block.header.state_root.to_vec(),
+    })
+}
+
+/// Client implementation for interacting with an OP-stack L2
 ///
-/// This struct provides a unified interface for interacting with both blockchain networks
+/// Cheap to clone, clones share the connections to the L2 endpoints.
+#[derive(Debug, Clone)]
+pub struct OpStackClient {
+    /// The configuration of the L2
+    pub config: OpStackChainConfig,
+    /// The connections to the L2 RPC endpoints
+    pub transport: Transport<EthereumConnection>,
+    /// Cache of the proofs fetched at L2 blocks with a finalized output root
+    pub cache: ProofCache,
+}
+
+impl OpStackClient {
+    /// Connects to the RPC endpoints of an L2
+    pub fn connect(config: OpStackChainConfig) -> Result<Self> {
+        Ok(Self {
+            transport: Transport::connect(
+                config.rpc_urls.clone(),
+                RetryConfig::from_env(),
+                EthereumConnection::connect,
+            )?,
+            config,
+            cache: ProofCache::from_env(),
+        })
+    }
+
+    /// Connects to the L2 of `domain_id`, configured in the environment
+    pub fn from_env(domain_id: &str) -> Result<Self> {
+        Self::connect(OpStackChainConfig::from_env(domain_id)?)
+    }
+
+    /// Queries the header fields of the L2 block at `number`
+    pub async fn get_block(&self, number: u64) -> Result<ExecutionBlock> {
+        get_execution_block(
+            &self.transport,
+            &self.config.domain_id,
+            BlockSelection::Number(number),
+        )
+        .await
+    }
+}
+
+/// Subscribes to the new heads of an Ethereum websocket endpoint
+async fn subscribe_ethereum_heads(ws_url: String) -> Result<BoxStream<'static, Result<()>>> {
+    let provider = ProviderBuilder::new()
+        .on_ws(WsConnect::new(ws_url))
+        .await
+        .map_err(|e| CoprocessorError::rpc("Ethereum websocket", e))?;
+    let subscription = provider
+        .subscribe_blocks()
+        .await
+        .map_err(|e| CoprocessorError::rpc("Ethereum new head subscription", e))?;
+    Ok(subscription
+        .into_stream()
+        .map(move |_| {
+            // the provider is owned by the stream, dropping it would close the subscription
+            let _provider = &provider;
+            Ok(())
+        })
+        .boxed())
+}
+
+/// A composite client that combines the Tendermint and Ethereum clients
+///
+/// This struct provides a unified interface for interacting with all blockchain networks
 /// simultaneously, which is useful for cross-chain operations.
 #[derive(Debug, Clone)]
 pub struct DefaultClient {
-    /// Instance of the Neutron client
-    pub neutron_client: NeutronClient,
+    /// Clients of the Tendermint domains, in the order of `TENDERMINT_DOMAINS`
+    pub tendermint_clients: Vec<TendermintClient>,
     /// Instance of the Ethereum client
     pub ethereum_client: EthereumClient,
+    /// Clients of the OP-stack L2 domains, in the order of `OP_STACK_DOMAINS`
+    pub op_stack_clients: Vec<OpStackClient>,
+}
+
+impl DefaultClient {
+    /// Connects to every chain configured in the environment
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            tendermint_clients: read_tendermint_domains()
+                .iter()
+                .map(|domain_id| TendermintClient::from_env(domain_id))
+                .collect::<Result<_>>()?,
+            ethereum_client: EthereumClient::from_env()?,
+            op_stack_clients: read_op_stack_domains()
+                .iter()
+                .map(|domain_id| OpStackClient::from_env(domain_id))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// The client of a Tendermint domain
+    ///
+    /// # Errors
+    /// Fails if no Tendermint domain with this id is configured
+    pub fn tendermint_client(&self, domain_id: &str) -> Result<&TendermintClient> {
+        self.tendermint_clients
+            .iter()
+            .find(|client| client.config.domain_id == domain_id)
+            .ok_or_else(|| {
+                CoprocessorError::Config(format!(
+                    "Tendermint domain {} is not configured",
+                    domain_id
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use beacon::{
+        extract_electra_block_body, get_beacon_block_header, get_electra_block, merkleize_header,
+        types::electra::ElectraBlockHeader,
+    };
+
+    use crate::{fixtures, read_ethereum_consensus_rpc_url};
+
+    /// Checks that the merkleized Electra block body and header match the roots of the beacon node
+    #[tokio::test]
+    #[ignore = "needs a Sepolia beacon node or recorded fixtures, see RPC_FIXTURE_MODE"]
+    async fn test_get_beacon_block_body() {
+        fixtures::install().unwrap();
+        let beacon_url = read_ethereum_consensus_rpc_url();
+        // an Electra block on Sepolia
+        let slot = 7520257;
+        let beacon_block_header = get_beacon_block_header(slot, &beacon_url).await.unwrap();
+        let electra_block = get_electra_block(slot, &beacon_url).await.unwrap();
+        let electra_block_body_root = extract_electra_block_body(electra_block).merkelize();
+        assert_eq!(
+            electra_block_body_root.to_vec(),
+            beacon_block_header.body_root.to_vec()
+        );
+        let electra_block_header = ElectraBlockHeader {
+            slot: beacon_block_header.slot.as_u64(),
+            proposer_index: beacon_block_header.proposer_index,
+            parent_root: beacon_block_header.parent_root.into(),
+            state_root: beacon_block_header.state_root.into(),
+            body_root: beacon_block_header.body_root.into(),
+        };
+        assert_eq!(
+            merkleize_header(electra_block_header).to_vec(),
+            beacon_block_header.canonical_root().to_vec()
+        );
+    }
 }
@@ -1,4 +0,0 @@
-pub const NEUTRON_HEIGHT_KEY: &[u8] = b"neutron_height";
-pub const ETHEREUM_HEIGHT_KEY: &[u8] = b"ethereum_height";
-pub const NEUTRON_ROOT_KEY: &[u8] = b"neutron_root";
-pub const ETHEREUM_ROOT_KEY: &[u8] = b"ethereum_root";
@@ -1,21 +1,41 @@
-use crate::{read_ethereum_rpc_url, read_neutron_rpc_url};
+use crate::{
+    cache::ProofCache,
+    clients::{DefaultClient, EthereumClient, EthereumConnection, TendermintClient},
+    domains::{Domain, DomainRegistry, LightClientConfig},
+    error::{CoprocessorError, Result, StorageProofFailure},
+    opstack::OpStackMerkleProofProvider,
+    read_coprocessor_data_dir, read_proof_fetch_concurrency,
+    storage::PersistentSmt,
+    transport::Transport,
+};
+use alloy::{
+    consensus::Account,
+    eips::BlockId,
+    primitives::{Address, B256, keccak256},
+    providers::Provider,
+};
+use anyhow::anyhow;
 use common_merkle_proofs::merkle::types::MerkleClient;
-use ethereum_merkle_proofs::{
-    ethereum_rpc::rpc::EvmMerkleRpcClient,
-    merkle_lib::types::{EthereumMerkleProof, decode_rlp_bytes},
+use coprocessor_circuit_types::{
+    ETHEREUM_DOMAIN_ID, EthereumAccountProofs, OpStackStorageProofs, SmtContext,
 };
+use ethereum_merkle_proofs::merkle_lib::types::EthereumMerkleProof;
+use futures::{StreamExt, stream};
 use ics23_merkle_proofs::{
     keys::Ics23Key, merkle_lib::types::Ics23MerkleProof, rpc::Ics23MerkleRpcClient,
 };
-use valence_coprocessor_core::MemorySmt;
+use std::str::FromStr;
+use valence_coprocessor_core::SmtOpening;
 
 pub type EthereumKey = Vec<u8>;
 
-pub struct NeutronMerkleProofProvider {
-    pub neutron_rpc_client: Ics23MerkleRpcClient,
+pub struct TendermintMerkleProofProvider {
+    /// The client of the chain the ICS23 proofs are requested from
+    pub client: TendermintClient,
 }
-impl NeutronMerkleProofProvider {
-    /// Fetches a storage proof for a given key at a specific block height
+impl TendermintMerkleProofProvider {
+    /// Fetches a storage proof for a given key at a specific block height, served from the
+    /// cache if it was fetched before
     ///
     /// # Arguments
     /// * `key` - The storage key to fetch the proof for
@@ -23,108 +43,480 @@ impl NeutronMerkleProofProvider {
     ///
     /// # Returns
     /// The raw storage proof bytes
-    async fn get_storage_proof(&self, key: &Ics23Key, height: u64) -> Vec<u8> {
-        self.neutron_rpc_client
-            .get_proof(&key.to_string(), "", height)
+    async fn get_storage_proof(&self, key: &Ics23Key, height: u64) -> Result<Vec<u8>> {
+        let key = key.to_string();
+        let domain_id = &self.client.config.domain_id;
+        let context = format!("{} storage proof for {}", domain_id, key);
+        self.client
+            .cache
+            .get_or_fetch(domain_id, height, key.as_bytes(), || {
+                self.client.transport.call(&context, |connection| {
+                    let (key, context) = (&key, &context);
+                    async move {
+                        // the ICS23 proof client opens its own HTTP client for every proof, only
+                        // the endpoint selection and retries are shared with the other requests
+                        Ics23MerkleRpcClient {
+                            rpc_url: connection.rpc_url,
+                        }
+                        .get_proof(key, "", height)
+                        .await
+                        .map_err(|e| CoprocessorError::rpc(context, e))
+                    }
+                })
+            })
             .await
-            .unwrap()
     }
 }
 pub struct EthereumMerkleProofProvider {
-    pub ethereum_rpc_client: EvmMerkleRpcClient,
+    /// The Ethereum client the account and storage proofs are requested with
+    pub ethereum_client: EthereumClient,
 }
 impl EthereumMerkleProofProvider {
-    async fn get_account_and_storage_proof(
+    /// Fetches the account proof of a contract and the proofs of many of its storage slots,
+    /// served from the cache if they were fetched before
+    ///
+    /// # Arguments
+    /// * `address` - The contract address
+    /// * `keys` - The storage slots to prove
+    /// * `ethereum_height` - The execution block height to fetch the proofs at
+    ///
+    /// # Returns
+    /// The account proof and the storage proofs, in the order of `keys`
+    pub(crate) async fn get_account_proofs(
         &self,
-        key: (EthereumKey, String),
+        address: &str,
+        keys: &[EthereumKey],
         ethereum_height: u64,
-    ) -> (EthereumMerkleProof, EthereumMerkleProof) {
-        let (account_proof, storage_proof) = self
-            .ethereum_rpc_client
-            .get_account_and_storage_proof(&alloy::hex::encode(&key.0), &key.1, ethereum_height)
-            .await
-            .unwrap();
-        (account_proof, storage_proof)
+    ) -> Result<EthereumAccountProofs> {
+        get_evm_account_proofs(
+            ETHEREUM_DOMAIN_ID,
+            &self.ethereum_client.transport,
+            &self.ethereum_client.cache,
+            address,
+            keys,
+            ethereum_height,
+        )
+        .await
+    }
+}
+
+/// Fetches the account proof of a contract on an EVM chain and the proofs of many of its
+/// storage slots, served from the cache if they were fetched before
+///
+/// # Arguments
+/// * `chain` - The domain id of the chain, also the namespace of the cache entries
+/// * `address` - The contract address
+/// * `keys` - The storage slots to prove
+/// * `height` - The block height to fetch the proofs at, which must be final
+///
+/// # Returns
+/// The account proof and the storage proofs, in the order of `keys`
+pub(crate) async fn get_evm_account_proofs(
+    chain: &str,
+    transport: &Transport<EthereumConnection>,
+    cache: &ProofCache,
+    address: &str,
+    keys: &[EthereumKey],
+    height: u64,
+) -> Result<EthereumAccountProofs> {
+    let cache_key = [address.to_lowercase().into_bytes(), keys.concat()].concat();
+    cache
+        .get_or_fetch(chain, height, &cache_key, || {
+            fetch_evm_account_proofs(chain, transport, address, keys, height)
+        })
+        .await
+}
+
+/// Fetches the account proof of a contract and the proofs of many of its storage slots
+/// with a single `eth_getProof` request
+async fn fetch_evm_account_proofs(
+    chain: &str,
+    transport: &Transport<EthereumConnection>,
+    address: &str,
+    keys: &[EthereumKey],
+    height: u64,
+) -> Result<EthereumAccountProofs> {
+    let context = format!("{} proofs of {} slots of {}", chain, keys.len(), address);
+    let contract = Address::from_str(address)
+        .map_err(|e| CoprocessorError::decoding("EVM contract address", e))?;
+    let slots = keys
+        .iter()
+        .map(|key| B256::try_from(key.as_slice()))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| CoprocessorError::decoding("EVM storage key", e))?;
+    let response = transport
+        .call(&context, |connection| {
+            let (slots, context) = (slots.clone(), &context);
+            async move {
+                connection
+                    .provider
+                    .get_proof(contract, slots)
+                    .block_id(BlockId::number(height))
+                    .await
+                    .map_err(|e| CoprocessorError::rpc(context, e))
+            }
+        })
+        .await?;
+    if response.storage_proof.len() != keys.len() {
+        return Err(CoprocessorError::decoding(
+            context,
+            anyhow!(
+                "Requested {} storage proofs, got {}",
+                keys.len(),
+                response.storage_proof.len()
+            ),
+        ));
     }
+    let account = Account {
+        nonce: response.nonce,
+        balance: response.balance,
+        storage_root: response.storage_hash,
+        code_hash: response.code_hash,
+    };
+    let account_proof = EthereumMerkleProof {
+        proof: response
+            .account_proof
+            .iter()
+            .map(|node| node.to_vec())
+            .collect(),
+        key: keccak256(contract).to_vec(),
+        value: alloy_rlp::encode(account),
+    };
+    let storage_proofs = slots
+        .iter()
+        .zip(response.storage_proof)
+        .map(|(slot, storage_proof)| EthereumMerkleProof {
+            proof: storage_proof
+                .proof
+                .iter()
+                .map(|node| node.to_vec())
+                .collect(),
+            key: keccak256(slot).to_vec(),
+            value: alloy_rlp::encode(storage_proof.value),
+        })
+        .collect();
+    Ok(EthereumAccountProofs {
+        account_proof,
+        storage_root: response.storage_hash.to_vec(),
+        storage_proofs,
+    })
 }
+
 pub struct Coprocessor {
-    /// The Sparse Merkle Tree used for storing and verifying proofs
-    pub smt_tree: MemorySmt,
+    /// The Sparse Merkle Tree used for storing and verifying proofs, persisted to the data directory
+    pub smt_tree: PersistentSmt,
     /// The current root hash of the SMT
     pub smt_root: [u8; 32],
-    /// RPC client for interacting with Neutron chain
-    pub neutron_coprocessor: NeutronMerkleProofProvider,
+    /// Proof providers of the Tendermint domains, one per configured chain
+    pub tendermint_coprocessors: Vec<TendermintMerkleProofProvider>,
     /// RPC client for interacting with Ethereum chain
     pub ethereum_coprocessor: EthereumMerkleProofProvider,
-    // light client specific
-    pub trusted_neutron_height: u64,
-    pub trusted_ethereum_height: u64,
-    pub target_neutron_height: u64,
-    pub trusted_neutron_root: Vec<u8>,
-    pub trusted_ethereum_root: Vec<u8>,
+    /// The domains proven by the zk light clients and stored in the SMT
+    pub domains: DomainRegistry,
+    /// Proof providers of the OP-stack L2 domains, proven through the Ethereum domain
+    pub op_stack_coprocessors: Vec<OpStackMerkleProofProvider>,
+    /// Ids of the applications that own a context in the SMT
+    pub applications: Vec<String>,
+    /// Maximum number of storage proofs requested concurrently per domain
+    pub proof_fetch_concurrency: usize,
 }
 
 impl Coprocessor {
-    pub fn from_env() -> Self {
-        let smt_tree = MemorySmt::default();
-        let smt_root = [0; 32];
-        let neutron_coprocessor = NeutronMerkleProofProvider {
-            neutron_rpc_client: Ics23MerkleRpcClient {
-                rpc_url: read_neutron_rpc_url(),
-            },
-        };
+    /// Opens the coprocessor state in the configured data directory
+    ///
+    /// # Arguments
+    /// * `clients` - The chain clients, shared with the proof providers
+    pub fn from_env(clients: &DefaultClient) -> Result<Self> {
+        let smt_tree =
+            PersistentSmt::open(read_coprocessor_data_dir()).map_err(CoprocessorError::Smt)?;
+        // resume from the last committed root instead of an empty tree
+        let smt_root = smt_tree.committed_root();
         let ethereum_coprocessor = EthereumMerkleProofProvider {
-            ethereum_rpc_client: EvmMerkleRpcClient {
-                rpc_url: read_ethereum_rpc_url(),
-            },
+            ethereum_client: clients.ethereum_client.clone(),
         };
-        Self {
+        let mut domains = DomainRegistry::default();
+        let mut tendermint_coprocessors = Vec::new();
+        for client in &clients.tendermint_clients {
+            domains.register(Domain::new(
+                &client.config.domain_id,
+                LightClientConfig::Tendermint(client.config.clone()),
+            ))?;
+            tendermint_coprocessors.push(TendermintMerkleProofProvider {
+                client: client.clone(),
+            });
+        }
+        domains.register(Domain::new(ETHEREUM_DOMAIN_ID, LightClientConfig::Helios))?;
+        let op_stack_coprocessors = clients
+            .op_stack_clients
+            .iter()
+            .map(|client| OpStackMerkleProofProvider {
+                client: client.clone(),
+                ethereum_client: clients.ethereum_client.clone(),
+            })
+            .collect();
+        Ok(Self {
             smt_tree,
             smt_root,
-            neutron_coprocessor,
+            tendermint_coprocessors,
             ethereum_coprocessor,
-            target_neutron_height: 0,
-            trusted_ethereum_height: 0,
-            trusted_neutron_height: 0,
-            trusted_ethereum_root: vec![],
-            trusted_neutron_root: vec![],
+            domains,
+            op_stack_coprocessors,
+            applications: Vec::new(),
+            proof_fetch_concurrency: read_proof_fetch_concurrency(),
+        })
+    }
+
+    /// The proof provider of a Tendermint domain
+    ///
+    /// # Errors
+    /// Fails if no Tendermint domain with this id is configured
+    pub fn tendermint_coprocessor(
+        &self,
+        domain_id: &str,
+    ) -> Result<&TendermintMerkleProofProvider> {
+        self.tendermint_coprocessors
+            .iter()
+            .find(|provider| provider.client.config.domain_id == domain_id)
+            .ok_or_else(|| {
+                CoprocessorError::Config(format!(
+                    "Tendermint domain {} is not configured",
+                    domain_id
+                ))
+            })
+    }
+
+    /// Fetches the storage proofs of L2 keys of an OP-stack domain, together with the proof of
+    /// the output root the L2 committed to Ethereum at `ethereum_height`
+    ///
+    /// # Errors
+    /// Fails if no OP-stack domain with this id is configured, see
+    /// `OpStackMerkleProofProvider::get_storage_proofs` otherwise
+    pub async fn get_op_stack_storage_proofs(
+        &self,
+        domain_id: &str,
+        ethereum_height: u64,
+        keys: Vec<(EthereumKey, String)>,
+    ) -> Result<OpStackStorageProofs> {
+        self.op_stack_coprocessors
+            .iter()
+            .find(|provider| provider.client.config.domain_id == domain_id)
+            .ok_or_else(|| {
+                CoprocessorError::Config(format!("OP-stack domain {} is not configured", domain_id))
+            })?
+            .get_storage_proofs(ethereum_height, keys, self.proof_fetch_concurrency)
+            .await
+    }
+
+    /// Registers an application, giving it its own context in the SMT
+    ///
+    /// The light-client context is reserved for the coprocessor and cannot be registered.
+    pub fn register_application(&mut self, application_id: &str) -> Result<()> {
+        if application_id == SmtContext::LightClients.name() {
+            return Err(CoprocessorError::Config(format!(
+                "Context {} is reserved for the light clients",
+                application_id
+            )));
         }
+        if self.applications.iter().any(|id| id == application_id) {
+            return Err(CoprocessorError::Config(format!(
+                "Application {} is already registered",
+                application_id
+            )));
+        }
+        self.applications.push(application_id.to_string());
+        Ok(())
     }
 
-    pub async fn get_storage_merkle_proofs(
+    /// Resolves the SMT context of a registered application
+    fn application_context<'a>(&self, application_id: &'a str) -> Result<SmtContext<'a>> {
+        if !self.applications.iter().any(|id| id == application_id) {
+            return Err(CoprocessorError::Config(format!(
+                "Application {} is not registered",
+                application_id
+            )));
+        }
+        Ok(SmtContext::Application(application_id))
+    }
+
+    /// Writes a value into the context of a registered application and advances the SMT root
+    ///
+    /// # Returns
+    /// The new SMT root, committed together with the next coprocessor update
+    pub fn insert_application_value(
         &mut self,
-        neutron_height: u64,
+        application_id: &str,
+        key: &[u8],
+        data: Vec<u8>,
+    ) -> Result<[u8; 32]> {
+        let context = self.application_context(application_id)?;
+        self.smt_root = self
+            .smt_tree
+            .insert(self.smt_root, context.name(), key, data)
+            .map_err(CoprocessorError::Smt)?;
+        Ok(self.smt_root)
+    }
+
+    /// Opens a key in the context of a registered application against `root`
+    pub fn get_application_opening(
+        &self,
+        application_id: &str,
+        root: [u8; 32],
+        key: &[u8],
+    ) -> Result<Option<SmtOpening>> {
+        let context = self.application_context(application_id)?;
+        self.smt_tree
+            .get_opening(context.name(), root, key)
+            .map_err(CoprocessorError::Smt)
+    }
+
+    /// Fetches the storage proofs of the keys of a Tendermint domain and of the Ethereum keys
+    ///
+    /// Both domains are queried in parallel, each with at most `proof_fetch_concurrency`
+    /// requests in flight. Ethereum keys are grouped by contract and every contract is proven
+    /// with a single request.
+    ///
+    /// # Arguments
+    /// * `tendermint_domain_id` - The Tendermint domain the ICS23 keys belong to
+    ///
+    /// # Returns
+    /// The Tendermint proofs in the order of the requested keys, and the proofs of every
+    /// Ethereum contract in the order the contracts first appear in the requested keys
+    ///
+    /// # Errors
+    /// `CoprocessorError::StorageProofs` listing every key whose proof could not be fetched
+    pub async fn get_storage_merkle_proofs(
+        &self,
+        tendermint_domain_id: &str,
+        tendermint_height: u64,
         ethereum_height: u64,
-        neutron_storage_keys: Vec<Ics23Key>,
+        tendermint_storage_keys: Vec<Ics23Key>,
         ethereum_storage_keys: Vec<(EthereumKey, String)>,
-    ) -> (
-        Vec<Ics23MerkleProof>,
-        Vec<(EthereumMerkleProof, EthereumMerkleProof, Vec<u8>)>,
-    ) {
-        let mut neutron_merkle_proofs: Vec<Ics23MerkleProof> = Vec::new();
-        let mut ethereum_merkle_proofs: Vec<(EthereumMerkleProof, EthereumMerkleProof, Vec<u8>)> =
-            Vec::new();
-        for key in neutron_storage_keys.iter() {
-            let proof = self
-                .neutron_coprocessor
-                .get_storage_proof(key, neutron_height)
-                .await;
-            let proof: Ics23MerkleProof = serde_json::from_slice(&proof).unwrap();
-            neutron_merkle_proofs.push(proof);
+    ) -> Result<(Vec<Ics23MerkleProof>, Vec<EthereumAccountProofs>)> {
+        let tendermint_coprocessor = self.tendermint_coprocessor(tendermint_domain_id)?;
+        let tendermint_proofs = stream::iter(&tendermint_storage_keys)
+            .map(|key| async move {
+                let proof = tendermint_coprocessor
+                    .get_storage_proof(key, tendermint_height)
+                    .await?;
+                serde_json::from_slice::<Ics23MerkleProof>(&proof).map_err(|e| {
+                    CoprocessorError::decoding(format!("{} storage proof", tendermint_domain_id), e)
+                })
+            })
+            // `buffered` yields the results in the order of the keys
+            .buffered(self.proof_fetch_concurrency)
+            .collect::<Vec<_>>();
+        let ethereum_contracts = group_by_contract(ethereum_storage_keys);
+        let ethereum_proofs = stream::iter(&ethereum_contracts)
+            .map(|(address, keys)| {
+                self.ethereum_coprocessor
+                    .get_account_proofs(address, keys, ethereum_height)
+            })
+            .buffered(self.proof_fetch_concurrency)
+            .collect::<Vec<_>>();
+        let (tendermint_results, ethereum_results) =
+            tokio::join!(tendermint_proofs, ethereum_proofs);
+
+        let mut failures = Vec::new();
+        let tendermint_merkle_proofs = collect_proofs(
+            tendermint_domain_id,
+            &tendermint_storage_keys,
+            tendermint_results,
+            |key| key.to_string(),
+            &mut failures,
+        );
+        let ethereum_merkle_proofs = collect_proofs(
+            ETHEREUM_DOMAIN_ID,
+            &ethereum_contracts,
+            ethereum_results,
+            |(address, keys)| format!("{} slots of {}", keys.len(), address),
+            &mut failures,
+        );
+        if !failures.is_empty() {
+            return Err(CoprocessorError::StorageProofs(failures));
         }
-        for key in ethereum_storage_keys.iter() {
-            let (account_proof, storage_proof) = self
-                .ethereum_coprocessor
-                .get_account_and_storage_proof((key.0.clone(), key.1.clone()), ethereum_height)
-                .await;
-            let account_decoded = decode_rlp_bytes(&account_proof.value).unwrap();
-            ethereum_merkle_proofs.push((
-                account_proof,
-                storage_proof,
-                account_decoded.get(2).unwrap().to_vec(),
-            ));
+        Ok((tendermint_merkle_proofs, ethereum_merkle_proofs))
+    }
+}
+
+/// Groups Ethereum storage keys by contract address, keeping the order of first appearance
+pub(crate) fn group_by_contract(
+    keys: Vec<(EthereumKey, String)>,
+) -> Vec<(String, Vec<EthereumKey>)> {
+    let mut contracts: Vec<(String, Vec<EthereumKey>)> = Vec::new();
+    for (key, address) in keys {
+        match contracts
+            .iter_mut()
+            .find(|(contract, _)| contract.eq_ignore_ascii_case(&address))
+        {
+            Some((_, contract_keys)) => contract_keys.push(key),
+            None => contracts.push((address, vec![key])),
         }
+    }
+    contracts
+}
+
+/// Splits the per-key results of a domain into the proofs and the failed keys
+pub(crate) fn collect_proofs<K, P>(
+    domain_id: &str,
+    keys: &[K],
+    results: Vec<Result<P>>,
+    describe_key: impl Fn(&K) -> String,
+    failures: &mut Vec<StorageProofFailure>,
+) -> Vec<P> {
+    let mut proofs = Vec::with_capacity(results.len());
+    for (index, (key, result)) in keys.iter().zip(results).enumerate() {
+        match result {
+            Ok(proof) => proofs.push(proof),
+            Err(error) => failures.push(StorageProofFailure {
+                domain_id: domain_id.to_string(),
+                index,
+                key: describe_key(key),
+                error,
+            }),
+        }
+    }
+    proofs
+}
+
+#[cfg(test)]
+mod test {
+    use super::{collect_proofs, group_by_contract};
+    use crate::error::CoprocessorError;
+
+    #[test]
+    fn test_collect_proofs_reports_failed_keys() {
+        let keys = vec!["a", "b", "c"];
+        let results = vec![
+            Ok(1),
+            Err(CoprocessorError::Verification("missing".to_string())),
+            Ok(3),
+        ];
+        let mut failures = Vec::new();
+        let proofs = collect_proofs(
+            "neutron",
+            &keys,
+            results,
+            |key| key.to_string(),
+            &mut failures,
+        );
+        assert_eq!(proofs, vec![1, 3]);
+        assert_eq!(failures.len(), 1);
+        assert_eq!((failures[0].index, failures[0].key.as_str()), (1, "b"));
+    }
 
-        (neutron_merkle_proofs, ethereum_merkle_proofs)
+    #[test]
+    fn test_group_by_contract() {
+        let contracts = group_by_contract(vec![
+            (vec![1], "0xAbc".to_string()),
+            (vec![2], "0xdef".to_string()),
+            (vec![3], "0xabc".to_string()),
+        ]);
+        assert_eq!(
+            contracts,
+            vec![
+                ("0xAbc".to_string(), vec![vec![1], vec![3]]),
+                ("0xdef".to_string(), vec![vec![2]]),
+            ]
+        );
     }
 }
@@ -0,0 +1,368 @@
+//! Domains whose state the coprocessor tracks.
+//!
+//! Each domain is identified by a domain id, proven by one kind of zk light client and stored
+//! in the coprocessor SMT under keys derived from its id. The registry holds any number of them.
+//!
+//! Any number of Tendermint (Cosmos SDK) chains can be tracked. Each is configured through
+//! environment variables prefixed with its domain id, e.g. `NEUTRON_RPC` and `NEUTRON_CHAIN_ID`.
+//! OP-stack L2 domains are configured the same way. They have no light client of their own and
+//! are proven through the output roots their chain commits to Ethereum.
+//!
+//! A Tendermint light client can only verify new headers while its trusted header is younger
+//! than the trusting period of the chain. Once it expired, the domain has to be re-anchored to a
+//! header approved by the chain's governance, configured with `<DOMAIN_ID>_REANCHOR_*`.
+
+use std::{
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use coprocessor_circuit_types::{
+    LightClientKind, NEUTRON_DOMAIN_ID, ProofMode, SmtKey, TrustedState,
+};
+use dotenvy::dotenv;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{CoprocessorError, Result},
+    fixtures::read_endpoint,
+    opstack::OutputRootSource,
+    transport::parse_endpoints,
+};
+
+/// Trusting period of a Tendermint chain if none is configured, two thirds of the common
+/// unbonding period of 21 days
+const DEFAULT_TRUSTING_PERIOD: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Configuration of a Tendermint (Cosmos SDK) chain tracked as a domain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TendermintChainConfig {
+    /// The domain id of the chain
+    pub domain_id: String,
+    /// The chain id, checked against every header fetched from the chain
+    pub chain_id: String,
+    /// The RPC endpoints, in the order they are tried
+    pub rpc_urls: Vec<String>,
+    /// The websocket endpoint announcing new blocks, `None` to poll instead
+    pub ws_url: Option<String>,
+    /// How long a trusted header can be used to verify newer headers
+    pub trusting_period: Duration,
+    /// The proof system the light client proofs are wrapped in
+    pub proof_mode: ProofMode,
+    /// A governance-approved header replacing the trusted header, `None` if not re-anchored
+    pub reanchor: Option<TrustedAnchor>,
+}
+
+/// A trusted header approved out of band, e.g. by a governance proposal of the chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustedAnchor {
+    /// The height of the approved header
+    pub height: u64,
+    /// The hash of the approved header
+    pub header_hash: Vec<u8>,
+    /// Reference to the approval, e.g. the governance proposal
+    pub approval: String,
+}
+
+/// A replacement of the trusted state of a domain by an approved header
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reanchor {
+    /// The trusted state that was replaced
+    pub replaced: TrustedState,
+    /// The approved state the domain continues from
+    pub anchor: TrustedState,
+    /// Unix time in seconds of the approved header
+    pub anchor_time: u64,
+    /// Reference to the approval, e.g. the governance proposal
+    pub approval: String,
+    /// Unix time in seconds the anchor was applied at
+    pub applied_at: u64,
+}
+
+impl TendermintChainConfig {
+    /// Reads the configuration of a chain from the `<DOMAIN_ID>_*` environment variables
+    ///
+    /// `<DOMAIN_ID>_RPC` (comma separated) and `<DOMAIN_ID>_CHAIN_ID` are required.
+    /// `<DOMAIN_ID>_TRUSTING_PERIOD_SECS` defaults to 14 days and `<DOMAIN_ID>_PROOF_MODE` to
+    /// `groth16`. A re-anchor is configured by `<DOMAIN_ID>_REANCHOR_HEIGHT`,
+    /// `<DOMAIN_ID>_REANCHOR_HASH` (hex) and `<DOMAIN_ID>_REANCHOR_APPROVAL`, which have to be set
+    /// together.
+    pub fn from_env(domain_id: &str) -> Result<Self> {
+        dotenv().ok();
+        let read = |name: &str| env::var(domain_env_var(domain_id, name)).ok();
+        let chain_id = read("CHAIN_ID").ok_or_else(|| {
+            CoprocessorError::Config(format!("Missing {}", domain_env_var(domain_id, "CHAIN_ID")))
+        })?;
+        let trusting_period = match read("TRUSTING_PERIOD_SECS") {
+            Some(seconds) => Duration::from_secs(seconds.parse().map_err(|_| {
+                CoprocessorError::Config(format!(
+                    "Invalid {}",
+                    domain_env_var(domain_id, "TRUSTING_PERIOD_SECS")
+                ))
+            })?),
+            None => DEFAULT_TRUSTING_PERIOD,
+        };
+        let proof_mode = match read("PROOF_MODE") {
+            Some(mode) => mode.parse().map_err(CoprocessorError::Config)?,
+            None => ProofMode::default(),
+        };
+        let reanchor = match (
+            read("REANCHOR_HEIGHT"),
+            read("REANCHOR_HASH"),
+            read("REANCHOR_APPROVAL"),
+        ) {
+            (None, None, None) => None,
+            (Some(height), Some(header_hash), Some(approval)) => Some(TrustedAnchor {
+                height: height.parse().map_err(|_| {
+                    CoprocessorError::Config(format!(
+                        "Invalid {}",
+                        domain_env_var(domain_id, "REANCHOR_HEIGHT")
+                    ))
+                })?,
+                header_hash: hex::decode(header_hash.trim_start_matches("0x")).map_err(|_| {
+                    CoprocessorError::Config(format!(
+                        "Invalid {}",
+                        domain_env_var(domain_id, "REANCHOR_HASH")
+                    ))
+                })?,
+                approval,
+            }),
+            _ => {
+                return Err(CoprocessorError::Config(format!(
+                    "Set all of {}, {} and {}",
+                    domain_env_var(domain_id, "REANCHOR_HEIGHT"),
+                    domain_env_var(domain_id, "REANCHOR_HASH"),
+                    domain_env_var(domain_id, "REANCHOR_APPROVAL")
+                )));
+            }
+        };
+        Ok(Self {
+            domain_id: domain_id.to_string(),
+            chain_id,
+            rpc_urls: read_domain_rpc_urls(domain_id)?,
+            ws_url: read_endpoint(&domain_env_var(domain_id, "WS_URL")),
+            trusting_period,
+            proof_mode,
+            reanchor,
+        })
+    }
+
+    /// The time a trusted header stays valid for
+    ///
+    /// # Arguments
+    /// * `trusted_time` - Unix time in seconds of the trusted header
+    /// * `now` - The current unix time in seconds
+    ///
+    /// # Errors
+    /// `CoprocessorError::Verification` if the trusting period has passed, the domain then has
+    /// to be re-anchored
+    pub fn remaining_trust(&self, trusted_time: u64, now: u64) -> Result<Duration> {
+        let expiry = trusted_time.saturating_add(self.trusting_period.as_secs());
+        if now >= expiry {
+            return Err(CoprocessorError::Verification(format!(
+                "The trusted {} header expired {} seconds ago, re-anchor it with {}",
+                self.domain_id,
+                now - expiry,
+                domain_env_var(&self.domain_id, "REANCHOR_*")
+            )));
+        }
+        Ok(Duration::from_secs(expiry - now))
+    }
+}
+
+/// Configuration of an OP-stack L2 tracked as a domain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpStackChainConfig {
+    /// The domain id of the L2
+    pub domain_id: String,
+    /// The L2 RPC endpoints, in the order they are tried
+    pub rpc_urls: Vec<String>,
+    /// The L1 contract the output roots of the L2 are read from
+    pub output_root_source: OutputRootSource,
+}
+
+impl OpStackChainConfig {
+    /// Reads the configuration of an L2 from the `<DOMAIN_ID>_*` environment variables
+    ///
+    /// `<DOMAIN_ID>_RPC` (comma separated) is required, together with either
+    /// `<DOMAIN_ID>_L2_OUTPUT_ORACLE` or `<DOMAIN_ID>_ANCHOR_STATE_REGISTRY`.
+    pub fn from_env(domain_id: &str) -> Result<Self> {
+        dotenv().ok();
+        Ok(Self {
+            domain_id: domain_id.to_string(),
+            rpc_urls: read_domain_rpc_urls(domain_id)?,
+            output_root_source: OutputRootSource::from_env(domain_id)?,
+        })
+    }
+}
+
+/// The name of the environment variable holding a setting of a domain, e.g. `NEUTRON_CHAIN_ID`
+/// for the chain id of `neutron`
+pub fn domain_env_var(domain_id: &str, name: &str) -> String {
+    format!("{}_{}", domain_id.to_uppercase().replace('-', "_"), name)
+}
+
+/// Reads the comma separated RPC endpoints of a domain from `<DOMAIN_ID>_RPC`
+fn read_domain_rpc_urls(domain_id: &str) -> Result<Vec<String>> {
+    let variable = domain_env_var(domain_id, "RPC");
+    let rpc_urls = read_endpoint(&variable)
+        .map(|urls| parse_endpoints(&urls))
+        .unwrap_or_default();
+    if rpc_urls.is_empty() {
+        return Err(CoprocessorError::Config(format!("Missing {}", variable)));
+    }
+    Ok(rpc_urls)
+}
+
+/// Reads the domain ids of the Tendermint chains from environment variables
+///
+/// # Returns
+/// The comma separated ids in `TENDERMINT_DOMAINS`, `neutron` if unset
+pub fn read_tendermint_domains() -> Vec<String> {
+    dotenv().ok();
+    env::var("TENDERMINT_DOMAINS")
+        .map(|domains| parse_endpoints(&domains))
+        .unwrap_or_else(|_| vec![NEUTRON_DOMAIN_ID.to_string()])
+}
+
+/// Reads the domain ids of the OP-stack L2s from environment variables
+///
+/// # Returns
+/// The comma separated ids in `OP_STACK_DOMAINS`, none if unset
+pub fn read_op_stack_domains() -> Vec<String> {
+    dotenv().ok();
+    env::var("OP_STACK_DOMAINS")
+        .map(|domains| parse_endpoints(&domains))
+        .unwrap_or_default()
+}
+
+/// The light client proving a domain, together with its configuration
+#[derive(Debug, Clone)]
+pub enum LightClientConfig {
+    Tendermint(TendermintChainConfig),
+    Helios,
+}
+
+/// A domain registered with the coprocessor
+#[derive(Debug, Clone)]
+pub struct Domain {
+    /// The domain id, also used to derive the SMT keys of the domain
+    pub id: String,
+    /// The light client proving the state of this domain
+    pub light_client: LightClientConfig,
+    /// The last state proven for this domain
+    pub trusted: TrustedState,
+    /// Unix time in seconds of the trusted header, `None` if unknown or if the light client
+    /// has no trusting period
+    pub trusted_time: Option<u64>,
+    /// The re-anchor the trusted state was replaced by, until an update from it is proven
+    pub pending_reanchor: Option<Reanchor>,
+    /// Every re-anchor proven so far, oldest first
+    pub reanchors: Vec<Reanchor>,
+    /// The height the next light client update should reach, ignored by light clients
+    /// that always advance to the latest finalized head
+    pub target_height: u64,
+}
+
+impl Domain {
+    pub fn new(id: &str, light_client: LightClientConfig) -> Self {
+        Self {
+            id: id.to_string(),
+            light_client,
+            trusted: TrustedState::default(),
+            trusted_time: None,
+            pending_reanchor: None,
+            reanchors: Vec::new(),
+            target_height: 0,
+        }
+    }
+
+    /// The kind of light client proving this domain
+    pub fn kind(&self) -> LightClientKind {
+        match self.light_client {
+            LightClientConfig::Tendermint(_) => LightClientKind::Tendermint,
+            LightClientConfig::Helios => LightClientKind::Helios,
+        }
+    }
+
+    /// SMT key of the latest proven height of this domain
+    pub fn height_key(&self) -> [u8; 32] {
+        SmtKey::DomainHeight(&self.id).derive()
+    }
+
+    /// SMT key of the latest proven root of this domain
+    pub fn root_key(&self) -> [u8; 32] {
+        SmtKey::DomainRoot(&self.id).derive()
+    }
+}
+
+/// The current unix time in seconds
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the unix epoch")
+        .as_secs()
+}
+
+/// The domains registered with the coprocessor, in registration order
+#[derive(Debug, Clone, Default)]
+pub struct DomainRegistry {
+    domains: Vec<Domain>,
+}
+
+impl DomainRegistry {
+    /// Registers a new domain, failing if the domain id is already taken
+    pub fn register(&mut self, domain: Domain) -> Result<()> {
+        if self.get(&domain.id).is_some() {
+            return Err(CoprocessorError::Config(format!(
+                "Domain {} is already registered",
+                domain.id
+            )));
+        }
+        self.domains.push(domain);
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Domain> {
+        self.domains.iter().find(|domain| domain.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut Domain> {
+        self.domains.iter_mut().find(|domain| domain.id == id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Domain> {
+        self.domains.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Domain> {
+        self.domains.iter_mut()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TendermintChainConfig;
+    use coprocessor_circuit_types::ProofMode;
+    use std::time::Duration;
+
+    #[test]
+    fn test_remaining_trust() {
+        let config = TendermintChainConfig {
+            domain_id: "neutron".to_string(),
+            chain_id: "pion-1".to_string(),
+            rpc_urls: vec!["http://localhost:26657".to_string()],
+            ws_url: None,
+            trusting_period: Duration::from_secs(100),
+            proof_mode: ProofMode::Groth16,
+            reanchor: None,
+        };
+        assert_eq!(
+            config.remaining_trust(1_000, 1_060).unwrap(),
+            Duration::from_secs(40)
+        );
+        // the trusted header is no longer valid at the end of the trusting period
+        assert!(config.remaining_trust(1_000, 1_100).is_err());
+        assert!(config.remaining_trust(1_000, 2_000).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+//! Errors of the coprocessor.
+//!
+//! Every fallible step of a coprocessor run surfaces as a `CoprocessorError`, classified by the
+//! stage that failed, so that callers can retry transient RPC failures and report the rest.
+
+use beacon::BeaconError;
+use thiserror::Error;
+
+/// Result type of the coprocessor
+pub type Result<T> = std::result::Result<T, CoprocessorError>;
+
+#[derive(Debug, Error)]
+pub enum CoprocessorError {
+    /// A request to a chain RPC or beacon node failed
+    #[error("RPC request failed: {context}")]
+    Rpc {
+        context: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// A response or proof output could not be decoded
+    #[error("Failed to decode {context}")]
+    Decoding {
+        context: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// A zk proof could not be generated
+    #[error("Failed to prove {context}")]
+    Proving {
+        context: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// A proof or proof output did not verify
+    #[error("Verification failed: {0}")]
+    Verification(String),
+    /// The SMT store failed or is missing a value
+    #[error("SMT error")]
+    Smt(#[source] anyhow::Error),
+    /// The trusted state checkpoint could not be read, written or applied
+    #[error("Checkpoint error")]
+    Checkpoint(#[source] anyhow::Error),
+    /// An RPC fixture could not be recorded or replayed
+    #[error("RPC fixture error")]
+    Fixture(#[source] anyhow::Error),
+    /// A domain or application was registered or used inconsistently
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    /// The storage proofs of one or more keys could not be fetched
+    #[error("Failed to fetch {} storage proofs", .0.len())]
+    StorageProofs(Vec<StorageProofFailure>),
+}
+
+/// The failure to fetch the storage proof of a single key
+#[derive(Debug)]
+pub struct StorageProofFailure {
+    /// The domain the key belongs to
+    pub domain_id: String,
+    /// Position of the key in the requested keys, or of the contract for Ethereum keys
+    /// which are fetched per contract
+    pub index: usize,
+    /// The key (or contract), as printed in errors
+    pub key: String,
+    pub error: CoprocessorError,
+}
+
+impl CoprocessorError {
+    pub fn rpc(context: impl Into<String>, source: impl Into<anyhow::Error>) -> Self {
+        Self::Rpc {
+            context: context.into(),
+            source: source.into(),
+        }
+    }
+
+    pub fn decoding(context: impl Into<String>, source: impl Into<anyhow::Error>) -> Self {
+        Self::Decoding {
+            context: context.into(),
+            source: source.into(),
+        }
+    }
+
+    pub fn proving(context: impl Into<String>, source: impl Into<anyhow::Error>) -> Self {
+        Self::Proving {
+            context: context.into(),
+            source: source.into(),
+        }
+    }
+
+    /// Whether the error is transient and the failed request may succeed when retried
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Rpc { .. })
+    }
+}
+
+impl From<BeaconError> for CoprocessorError {
+    fn from(error: BeaconError) -> Self {
+        match error {
+            BeaconError::Request(_) => Self::rpc("beacon node", error),
+            BeaconError::Decoding(_) | BeaconError::UnexpectedFork(_) => {
+                Self::decoding("beacon node response", error)
+            }
+        }
+    }
+}
@@ -1,27 +1,49 @@
-use crate::read_ethereum_consensus_rpc_url;
+use super::decode_height;
 use crate::{
-    MAILBOX_APPLICATION_CIRCUIT_ELF, coprocessor::Coprocessor, get_execution_block_height,
+    MAILBOX_APPLICATION_CIRCUIT_ELF,
+    coprocessor::Coprocessor,
+    domains::LightClientConfig,
+    error::{CoprocessorError, Result},
 };
 use alloy::sol_types::SolValue;
 use alloy_primitives::U256;
+use anyhow::anyhow;
 use beacon::types::electra::ElectraBlockHeader;
 use beacon::{extract_electra_block_body, get_beacon_block_header, get_electra_block};
+use coprocessor_circuit_types::{
+    DomainOpenings, ETHEREUM_DOMAIN_ID, NEUTRON_DOMAIN_ID, find_domain_openings,
+};
 use dotenvy::dotenv;
 use ethereum_merkle_proofs::merkle_lib::keccak::digest_keccak;
 use ics23_merkle_proofs::keys::Ics23Key;
 use sp1_sdk::{ProverClient, SP1Stdin};
 use std::env;
-use tendermint::block::Header;
-use valence_coprocessor_core::SmtOpening;
 use zk_mailbox_application_types::MailboxApplicationCircuitInputs;
 
 pub async fn prove(
-    neutron_height_opening: SmtOpening,
-    ethereum_height_opening: SmtOpening,
-    neutron_root_opening: SmtOpening,
-    ethereum_root_opening: SmtOpening,
-    neutron_block_header: Header,
-) {
+    coprocessor: &mut Coprocessor,
+    coprocessor_root: [u8; 32],
+    domain_openings: Vec<DomainOpenings>,
+) -> Result<()> {
+    // the Neutron mailbox is proven against the app hash of a Tendermint light client
+    match coprocessor
+        .domains
+        .get(NEUTRON_DOMAIN_ID)
+        .map(|domain| &domain.light_client)
+    {
+        Some(LightClientConfig::Tendermint(_)) => {}
+        _ => {
+            return Err(CoprocessorError::Config(
+                "The mailbox needs Neutron configured as a Tendermint domain".to_string(),
+            ));
+        }
+    }
+    let neutron_height_opening = &find_domain_openings(&domain_openings, NEUTRON_DOMAIN_ID)
+        .ok_or_else(|| CoprocessorError::Smt(anyhow!("Missing Neutron domain openings")))?
+        .height_opening;
+    let ethereum_height_opening = &find_domain_openings(&domain_openings, ETHEREUM_DOMAIN_ID)
+        .ok_or_else(|| CoprocessorError::Smt(anyhow!("Missing Ethereum domain openings")))?
+        .height_opening;
     // we want to prove the Neutron mailbox message at key 1 e.g. the first message that is "Hello Ethereum!"
     // when proving a value in ZK, the app developer should be confident that it exists on the target domain
     // If it doesn't exist, then the prover will fail
@@ -35,20 +57,25 @@ pub async fn prove(
     let encoded_key = (counter, slot).abi_encode();
     // we want to prove the Ethereum mailbox message at key 1 e.g. the first message that is "Hello Neutron!"
     let ethereum_mailbox_messages_key = digest_keccak(&encoded_key).to_vec();
-    let mut coprocessor = Coprocessor::from_env();
-    // todo: get the real ethereum height from the beacon block height
-    let beacon_block_slot =
-        u64::from_be_bytes(ethereum_height_opening.data.clone().try_into().unwrap());
+    let beacon_block_slot = decode_height(&ethereum_height_opening.data)?;
+    let ethereum_client = coprocessor.ethereum_coprocessor.ethereum_client.clone();
+    // beacon blocks at finalized slots never change, so reruns take them from the cache
+    let (beacon_transport, beacon_cache) = (&ethereum_client.beacon, &ethereum_client.cache);
     // Get the Ethereum execution layer block height for the beacon consensus slot
-    let ethereum_height =
-        get_execution_block_height(&read_ethereum_consensus_rpc_url(), beacon_block_slot)
-            .await
-            .unwrap();
-    let neutron_target_height =
-        u64::from_be_bytes(neutron_height_opening.data.clone().try_into().unwrap());
+    let ethereum_height = ethereum_client
+        .get_execution_block_at_slot(beacon_block_slot)
+        .await?
+        .number;
+    let neutron_target_height = decode_height(&neutron_height_opening.data)?;
+    let neutron_block_header = coprocessor
+        .tendermint_coprocessor(NEUTRON_DOMAIN_ID)?
+        .client
+        .get_header_at_height(neutron_target_height)
+        .await?;
     // Get the Merkle proofs for the Neutron and Ethereum mailbox keys that we constructed above
     let domain_state_proofs = coprocessor
         .get_storage_merkle_proofs(
+            NEUTRON_DOMAIN_ID,
             // the app hash of the current block attests to the state of the previous block
             neutron_target_height - 1,
             ethereum_height,
@@ -58,16 +85,26 @@ pub async fn prove(
                 read_ethereum_mailbox_example_contract_address(),
             )],
         )
-        .await;
-    let ethereum_slot =
-        u64::from_be_bytes(ethereum_height_opening.data.clone().try_into().unwrap());
+        .await?;
+    let ethereum_slot = beacon_block_slot;
     // Get the Electra signed block object from the RPC
-    let electra_block = get_electra_block(ethereum_slot, &read_ethereum_consensus_rpc_url()).await;
+    let electra_block = beacon_cache
+        .get_or_fetch("beacon", ethereum_slot, b"block", || {
+            beacon_transport.call("Electra block", |beacon_url| async move {
+                Ok(get_electra_block(ethereum_slot, &beacon_url).await?)
+            })
+        })
+        .await?;
     // Extract the body roots from the Electra block
     let electra_body_roots = extract_electra_block_body(electra_block);
     // Get the Electra block header from the RPC
-    let electra_block_header =
-        get_beacon_block_header(ethereum_slot, &read_ethereum_consensus_rpc_url()).await;
+    let electra_block_header = beacon_cache
+        .get_or_fetch("beacon", ethereum_slot, b"header", || {
+            beacon_transport.call("beacon block header", |beacon_url| async move {
+                Ok(get_beacon_block_header(ethereum_slot, &beacon_url).await?)
+            })
+        })
+        .await?;
     // Construct the Zk-friendly Electra block header object
     let electra_block_header = ElectraBlockHeader {
         slot: electra_block_header.slot.as_u64(),
@@ -81,25 +118,26 @@ pub async fn prove(
     let mailbox_inputs = MailboxApplicationCircuitInputs {
         neutron_storage_proofs: domain_state_proofs.0,
         ethereum_storage_proofs: domain_state_proofs.1,
-        neutron_height_opening,
-        ethereum_height_opening,
-        neutron_root_opening,
-        ethereum_root_opening,
+        domain_openings,
         neutron_block_header,
         electra_block_header,
         electra_body_roots,
-        coprocessor_root: coprocessor.smt_root,
+        coprocessor_root,
     };
     // Run the Prover for the Application Circuit
     let prover = ProverClient::from_env();
     let mut stdin = SP1Stdin::new();
     let (pk, _) = prover.setup(MAILBOX_APPLICATION_CIRCUIT_ELF);
-    stdin.write_slice(&serde_json::to_vec(&mailbox_inputs).unwrap());
+    stdin.write_slice(
+        &serde_json::to_vec(&mailbox_inputs)
+            .map_err(|e| CoprocessorError::decoding("mailbox application inputs", e))?,
+    );
     let _proof = prover
         .prove(&pk, &stdin)
         .groth16()
         .run()
-        .expect("Failed to prove Mailbox Application!");
+        .map_err(|e| CoprocessorError::proving("mailbox application", e))?;
+    Ok(())
 }
 
 /// Reads the Ethereum mailbox example contract address from environment variables
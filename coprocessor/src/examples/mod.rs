@@ -1,142 +1,168 @@
-use alloy::dyn_abi::SolType;
-use coprocessor_circuit_types::CoprocessorCircuitInputs;
-use sha2::{Digest, Sha256};
-use sp1_helios_primitives::types::ProofOutputs;
+use anyhow::anyhow;
+use coprocessor_circuit_types::{
+    CoprocessorCircuitInputs, CoprocessorCircuitOutputs, DomainOpenings, DomainUpdate, SmtContext,
+    SmtInsertion,
+};
 use sp1_sdk::{HashableKey, ProverClient, SP1Stdin};
 use sp1_verifier::Groth16Verifier;
-use tendermint_program_types::TendermintOutput;
 
 use crate::{
     COPROCESSOR_CIRCUIT_ELF,
-    constants::{ETHEREUM_HEIGHT_KEY, ETHEREUM_ROOT_KEY, NEUTRON_HEIGHT_KEY, NEUTRON_ROOT_KEY},
+    checkpoint::Checkpoint,
     coprocessor::Coprocessor,
-    lightclients::{helios::SP1HeliosOperator, tendermint::SP1TendermintOperator},
+    domains::Domain,
+    error::{CoprocessorError, Result},
+    lightclients::{LightClientUpdate, prove_domain_update},
 };
 
 #[cfg(feature = "mailbox")]
 pub mod mailbox;
 
-pub async fn prove_coprocessor(coprocessor: &mut Coprocessor) -> (TendermintOutput, ProofOutputs) {
-    let mut ethereum_operator = SP1HeliosOperator::new();
-    // todo: remove hardcoded ethereum height and replace it with a real trusted height
-    // in production we take our last trusted slot and calculate the current period from the last finalized slot.
-    // then we request the update, generate the proof and verify it against the active sync committee.
-    // if the proof is valid for that committee and the slot height is greater than the last trusted slot,
-    // we update the trusted height and root, as well as the sync committee (if it changed)
-    let ethereum_light_client_proof = ethereum_operator.run(7553024 - (32 * 8192), 33).await;
-    let ethereum_light_client_proof = ethereum_light_client_proof.unwrap().unwrap();
-    let helios_proof_serialized = ethereum_light_client_proof.bytes();
-    let helios_public_values = ethereum_light_client_proof.public_values.to_vec();
-    let helios_vk = ethereum_operator.get_vk();
-    let neutron_operator = SP1TendermintOperator::new(
-        coprocessor.trusted_neutron_height,
-        coprocessor.target_neutron_height,
-    );
-    let neutron_light_client_proof = neutron_operator.run().await;
-    let neutron_proof_serialized = neutron_light_client_proof.bytes();
-    let neutron_public_values = neutron_light_client_proof.public_values.to_vec();
-    let neutron_vk = neutron_operator.get_vk();
-    let neutron_output: TendermintOutput =
-        serde_json::from_slice(&neutron_light_client_proof.public_values.to_vec()).unwrap();
-    let helios_output: ProofOutputs =
-        ProofOutputs::abi_decode(&ethereum_light_client_proof.public_values.to_vec(), false)
-            .unwrap();
-    let target_neutron_root: Vec<u8> = neutron_output.target_header_hash.to_vec();
-    let target_ethereum_root: Vec<u8> = helios_output.newHeader.to_vec();
-    let target_neutron_height: u64 = neutron_output.target_height;
-    let target_ethereum_height: u64 = helios_output.newHead.try_into().unwrap();
-    let mut coprocessor_root = coprocessor.smt_root;
-    let mut hasher = Sha256::new();
-    hasher.update(NEUTRON_HEIGHT_KEY);
-    let neutron_height_key = hasher.finalize();
-    let mut hasher = Sha256::new();
-    hasher.update(ETHEREUM_HEIGHT_KEY);
-    let ethereum_height_key = hasher.finalize();
-    let mut hasher = Sha256::new();
-    hasher.update(NEUTRON_ROOT_KEY);
-    let neutron_root_key = hasher.finalize();
-    let mut hasher = Sha256::new();
-    hasher.update(ETHEREUM_ROOT_KEY);
-    let ethereum_root_key = hasher.finalize();
-    coprocessor_root = coprocessor
+/// Opens the height and root of a domain in the coprocessor SMT at `root`
+///
+/// # Returns
+/// `None` if no state has been stored for the domain under `root`
+pub fn get_domain_openings(
+    coprocessor: &Coprocessor,
+    domain: &Domain,
+    root: [u8; 32],
+) -> Result<Option<DomainOpenings>> {
+    let height_opening = coprocessor
         .smt_tree
-        .insert(
-            coprocessor_root,
-            "demo",
-            &neutron_height_key,
-            target_neutron_height.to_be_bytes().to_vec(),
-        )
-        .expect("Failed to insert Neutron Height");
-    coprocessor_root = coprocessor
-        .smt_tree
-        .insert(
-            coprocessor_root,
-            "demo",
-            &ethereum_height_key,
-            target_ethereum_height.to_be_bytes().to_vec(),
-        )
-        .expect("Failed to insert Ethereum Height");
-    coprocessor_root = coprocessor
+        .get_opening(SmtContext::LightClients.name(), root, &domain.height_key())
+        .map_err(CoprocessorError::Smt)?;
+    let root_opening = coprocessor
         .smt_tree
-        .insert(
-            coprocessor_root,
-            "demo",
-            &neutron_root_key,
-            target_neutron_root.clone(),
+        .get_opening(SmtContext::LightClients.name(), root, &domain.root_key())
+        .map_err(CoprocessorError::Smt)?;
+    Ok(height_opening
+        .zip(root_opening)
+        .map(|(height_opening, root_opening)| DomainOpenings {
+            domain_id: domain.id.clone(),
+            height_opening,
+            root_opening,
+        }))
+}
+
+/// Decodes a big-endian height stored in a domain height opening
+pub(crate) fn decode_height(data: &[u8]) -> Result<u64> {
+    let bytes: [u8; 8] = data.try_into().map_err(|_| {
+        CoprocessorError::decoding(
+            "domain height",
+            anyhow!("Expected 8 bytes, got {}", data.len()),
         )
-        .expect("Failed to insert Ethereum Root");
-    coprocessor_root = coprocessor
+    })?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Builds the witnesses of every insert made since the last committed root
+fn get_insertion_witnesses(coprocessor: &Coprocessor) -> Result<Vec<SmtInsertion>> {
+    coprocessor
         .smt_tree
-        .insert(
-            coprocessor_root,
-            "demo",
-            &ethereum_root_key,
-            target_ethereum_root.clone(),
-        )
-        .expect("Failed to insert Ethereum Root");
+        .pending_insertions()
+        .iter()
+        .map(|entry| {
+            let opening = coprocessor
+                .smt_tree
+                .get_opening(&entry.context, entry.root, &entry.key)
+                .map_err(CoprocessorError::Smt)?
+                .ok_or_else(|| CoprocessorError::Smt(anyhow!("Missing opening of an insert")))?;
+            let previous_data = coprocessor
+                .smt_tree
+                .get_opening(&entry.context, entry.previous_root, &entry.key)
+                .map_err(CoprocessorError::Smt)?
+                .map(|opening| opening.data);
+            Ok(SmtInsertion {
+                context: entry.context.clone(),
+                opening,
+                previous_data,
+                root: entry.root,
+            })
+        })
+        .collect()
+}
+
+/// Proves a coprocessor update on top of the last committed root
+///
+/// # Returns
+/// The circuit outputs, chaining the new root to the previous one
+pub async fn prove_coprocessor(coprocessor: &mut Coprocessor) -> Result<CoprocessorCircuitOutputs> {
+    // the update extends the root committed by the previous update
+    let previous_coprocessor_root = coprocessor.smt_tree.committed_root();
+    // prove the next light client update of every registered domain that has a new head
+    let mut light_client_updates: Vec<(String, LightClientUpdate)> = Vec::new();
+    for domain in coprocessor.domains.iter() {
+        match prove_domain_update(coprocessor, domain).await? {
+            Some(update) => light_client_updates.push((domain.id.clone(), update)),
+            // the domain keeps the height and root stored under the previous root
+            None => println!("No new {} head, skipping the domain", domain.id),
+        }
+    }
+    if light_client_updates.is_empty() {
+        return Err(CoprocessorError::proving(
+            "coprocessor update",
+            anyhow!("No domain has a new head"),
+        ));
+    }
+    // insert the new heights and roots into the SMT
+    let mut coprocessor_root = coprocessor.smt_root;
+    for (domain_id, update) in light_client_updates.iter() {
+        let domain = coprocessor.domains.get(domain_id).unwrap();
+        let (height_key, root_key) = (domain.height_key(), domain.root_key());
+        coprocessor_root = coprocessor
+            .smt_tree
+            .insert(
+                coprocessor_root,
+                SmtContext::LightClients.name(),
+                &height_key,
+                update.target.height.to_be_bytes().to_vec(),
+            )
+            .map_err(CoprocessorError::Smt)?;
+        coprocessor_root = coprocessor
+            .smt_tree
+            .insert(
+                coprocessor_root,
+                SmtContext::LightClients.name(),
+                &root_key,
+                update.target.root.clone(),
+            )
+            .map_err(CoprocessorError::Smt)?;
+    }
     coprocessor.smt_root = coprocessor_root;
-    let neutron_height_opening = coprocessor
-        .smt_tree
-        .get_opening("demo", coprocessor_root, &neutron_height_key)
-        .unwrap()
-        .unwrap();
-    let ethereum_height_opening = coprocessor
-        .smt_tree
-        .get_opening("demo", coprocessor_root, &ethereum_height_key)
-        .unwrap()
-        .unwrap();
-    let neutron_root_opening = coprocessor
-        .smt_tree
-        .get_opening("demo", coprocessor_root, &neutron_root_key)
-        .unwrap()
-        .unwrap();
-    let ethereum_root_opening = coprocessor
-        .smt_tree
-        .get_opening("demo", coprocessor_root, &ethereum_root_key)
-        .unwrap()
-        .unwrap();
-    coprocessor.trusted_neutron_height = neutron_output.trusted_height;
-    coprocessor.trusted_ethereum_height = helios_output.prevHead.try_into().unwrap();
-    coprocessor.trusted_neutron_root = neutron_output.target_header_hash.to_vec();
-    coprocessor.trusted_ethereum_root = helios_output.prevHeader.to_vec();
+    let domain_updates: Vec<DomainUpdate> = light_client_updates
+        .iter()
+        .map(|(domain_id, update)| {
+            let domain = coprocessor.domains.get(domain_id).unwrap();
+            Ok(DomainUpdate {
+                kind: domain.kind(),
+                proofs: update.proofs.clone(),
+                previous: update.previous.clone(),
+                // a re-anchored domain opens the state it replaces rather than continuing from it
+                previous_openings: get_domain_openings(
+                    coprocessor,
+                    domain,
+                    previous_coprocessor_root,
+                )?,
+                reanchored: domain.pending_reanchor.is_some(),
+                openings: get_domain_openings(coprocessor, domain, coprocessor_root)?.ok_or_else(
+                    || {
+                        CoprocessorError::Smt(anyhow!(
+                            "Missing openings of the inserted domain state"
+                        ))
+                    },
+                )?,
+            })
+        })
+        .collect::<Result<_>>()?;
+    let insertions = get_insertion_witnesses(coprocessor)?;
     let coprocessor_inputs = CoprocessorCircuitInputs {
-        helios_proof: helios_proof_serialized,
-        helios_public_values,
-        helios_vk,
-        neutron_proof: neutron_proof_serialized,
-        neutron_public_values,
-        neutron_vk,
-        previous_neutron_height: coprocessor.trusted_neutron_height,
-        previous_ethereum_height: coprocessor.trusted_ethereum_height,
-        previous_neutron_root: coprocessor.trusted_neutron_root.to_vec(),
-        previous_ethereum_root: coprocessor.trusted_ethereum_root.to_vec(),
-        neutron_height_opening,
-        ethereum_height_opening,
-        neutron_root_opening,
-        ethereum_root_opening,
+        previous_coprocessor_root,
+        domain_updates,
+        insertions,
         coprocessor_root,
     };
-    let coprocessor_circuit_inputs_serialized = borsh::to_vec(&coprocessor_inputs).unwrap();
+    let coprocessor_circuit_inputs_serialized = borsh::to_vec(&coprocessor_inputs)
+        .map_err(|e| CoprocessorError::decoding("coprocessor circuit inputs", e))?;
     let client = ProverClient::from_env();
     let mut stdin = SP1Stdin::new();
     stdin.write_vec(coprocessor_circuit_inputs_serialized);
@@ -148,7 +174,7 @@ pub async fn prove_coprocessor(coprocessor: &mut Coprocessor) -> (TendermintOutp
         .prove(&pk, &stdin)
         .groth16()
         .run()
-        .expect("Failed to prove");
+        .map_err(|e| CoprocessorError::proving("coprocessor update", e))?;
     // This verification should happen on-chain
     // our co-processor must adapt the new state
     // We must serialize the circuit outputs so that the
@@ -160,26 +186,50 @@ pub async fn prove_coprocessor(coprocessor: &mut Coprocessor) -> (TendermintOutp
         &vk.bytes32(),
         groth16_vk,
     )
-    .unwrap();
-    (neutron_output, helios_output)
-}
-
-#[test]
-fn generate_constants() {
-    let mut hasher = Sha256::new();
-    hasher.update(NEUTRON_HEIGHT_KEY);
-    let neutron_height_key = hasher.finalize();
-    let mut hasher = Sha256::new();
-    hasher.update(ETHEREUM_HEIGHT_KEY);
-    let ethereum_height_key = hasher.finalize();
-    let mut hasher = Sha256::new();
-    hasher.update(NEUTRON_ROOT_KEY);
-    let neutron_root_key = hasher.finalize();
-    let mut hasher = Sha256::new();
-    hasher.update(ETHEREUM_ROOT_KEY);
-    let ethereum_root_key = hasher.finalize();
-    println!("neutron_height_key: {:?}", neutron_height_key);
-    println!("ethereum_height_key: {:?}", ethereum_height_key);
-    println!("neutron_root_key: {:?}", neutron_root_key);
-    println!("ethereum_root_key: {:?}", ethereum_root_key);
+    .map_err(|e| CoprocessorError::Verification(format!("Coprocessor proof: {}", e)))?;
+    let coprocessor_outputs: CoprocessorCircuitOutputs =
+        borsh::from_slice(&proof.public_values.to_vec())
+            .map_err(|e| CoprocessorError::decoding("coprocessor circuit outputs", e))?;
+    if coprocessor_outputs.coprocessor_root != coprocessor_root {
+        return Err(CoprocessorError::Verification(format!(
+            "Proven root {} does not match the SMT root {}",
+            hex::encode(coprocessor_outputs.coprocessor_root),
+            hex::encode(coprocessor_root)
+        )));
+    }
+    // the update is proven, so the new root and heads become the state we resume from. The
+    // archive records the height of every domain under the new root, including skipped ones
+    let domain_heights = coprocessor
+        .domains
+        .iter()
+        .filter_map(|domain| get_domain_openings(coprocessor, domain, coprocessor_root).transpose())
+        .map(|openings| {
+            let openings = openings?;
+            Ok((
+                openings.domain_id,
+                decode_height(&openings.height_opening.data)?,
+            ))
+        })
+        .collect::<Result<_>>()?;
+    let mut domains = coprocessor.domains.clone();
+    for (domain_id, update) in light_client_updates {
+        let domain = domains.get_mut(&domain_id).unwrap();
+        domain.trusted = update.target;
+        domain.trusted_time = update.target_time;
+        // the re-anchor is proven now, so it becomes part of the checkpoint
+        if let Some(reanchor) = domain.pending_reanchor.take() {
+            domain.reanchors.push(reanchor);
+        }
+    }
+    // the checkpoint is committed together with the root, the domains only advance once both
+    // are on disk
+    let checkpoint = Checkpoint::new(&domains, coprocessor_root)
+        .encode()
+        .map_err(CoprocessorError::Checkpoint)?;
+    coprocessor
+        .smt_tree
+        .commit(coprocessor_root, domain_heights, &checkpoint)
+        .map_err(CoprocessorError::Smt)?;
+    coprocessor.domains = domains;
+    Ok(coprocessor_outputs)
 }
@@ -0,0 +1,458 @@
+//! Record and replay of RPC traffic for offline runs.
+//!
+//! With `RPC_FIXTURE_MODE=record` every RPC endpoint configured in the environment is routed
+//! through a local proxy that forwards the requests upstream and writes each response into a
+//! fixture file under `RPC_FIXTURE_DIR`. With `RPC_FIXTURE_MODE=replay` the same proxies answer
+//! from the fixture files without touching the network, so that a recorded run can be repeated
+//! deterministically.
+//!
+//! Fixtures are keyed by the HTTP method, path and request body. JSON-RPC request ids are left
+//! out of the key and patched into replayed responses, since clients number their requests freely.
+//!
+//! Websocket subscriptions can not be replayed, so the websocket endpoints are switched off while
+//! fixtures are in use and new heads are polled through the proxied RPC endpoints instead.
+
+use std::{
+    collections::HashMap,
+    env, fs, iter,
+    net::TcpListener,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+};
+
+use alloy::transports::http::reqwest;
+use anyhow::anyhow;
+use axum::{
+    Router,
+    body::Bytes,
+    extract::State,
+    http::{HeaderValue, Method, StatusCode, Uri, header::CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use dotenvy::dotenv;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    domains::{domain_env_var, read_op_stack_domains, read_tendermint_domains},
+    error::{CoprocessorError, Result},
+    transport::parse_endpoints,
+};
+
+/// The endpoints replacing the configured ones while fixtures are in use, by environment variable
+///
+/// `None` switches an endpoint off. Set once by [`install`], before any client is created.
+static ROUTES: OnceLock<HashMap<String, Option<String>>> = OnceLock::new();
+
+/// Reads an endpoint from environment variables, routed through its fixture proxy if installed
+///
+/// # Returns
+/// The proxy url if the endpoint is recorded, `None` if it is switched off by fixtures, the
+/// value of the variable otherwise
+pub fn read_endpoint(variable: &str) -> Option<String> {
+    match ROUTES.get().and_then(|routes| routes.get(variable)) {
+        Some(route) => route.clone(),
+        None => env::var(variable).ok(),
+    }
+}
+
+/// Environment variables holding RPC endpoints, with the name of their fixture directory
+///
+/// Every Tendermint and OP-stack domain is recorded into a directory named after its domain id.
+fn routed_endpoints() -> Vec<(String, String)> {
+    read_tendermint_domains()
+        .into_iter()
+        .chain(read_op_stack_domains())
+        .map(|domain_id| (domain_env_var(&domain_id, "RPC"), domain_id))
+        .chain([
+            ("ETHEREUM_URL".to_string(), "ethereum".to_string()),
+            ("SOURCE_CONSENSUS_RPC_URL".to_string(), "beacon".to_string()),
+        ])
+        .collect()
+}
+
+/// Environment variables holding websocket endpoints, which are switched off by fixtures
+fn websocket_endpoints() -> Vec<String> {
+    read_tendermint_domains()
+        .iter()
+        .map(|domain_id| domain_env_var(domain_id, "WS_URL"))
+        .chain(["ETHEREUM_WS_URL".to_string()])
+        .collect()
+}
+
+/// Whether RPC traffic is recorded into or replayed from fixtures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixtureMode {
+    /// Forward requests upstream and write the responses to fixture files
+    Record,
+    /// Answer requests from fixture files only
+    Replay,
+}
+
+impl FixtureMode {
+    /// Reads `RPC_FIXTURE_MODE`, `None` if fixtures are disabled
+    pub fn from_env() -> Result<Option<Self>> {
+        dotenv().ok();
+        match env::var("RPC_FIXTURE_MODE").ok().as_deref() {
+            None | Some("") | Some("off") => Ok(None),
+            Some("record") => Ok(Some(Self::Record)),
+            Some("replay") => Ok(Some(Self::Replay)),
+            Some(mode) => Err(CoprocessorError::Config(format!(
+                "Invalid RPC_FIXTURE_MODE {}, expected record, replay or off",
+                mode
+            ))),
+        }
+    }
+}
+
+/// A recorded HTTP exchange
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fixture {
+    pub method: String,
+    /// Path and query of the request
+    pub path: String,
+    pub request: String,
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub response: String,
+}
+
+/// Serves the requests of one endpoint from fixtures, recording them first in record mode
+struct FixtureProxy {
+    mode: FixtureMode,
+    /// The url requests are forwarded to in record mode
+    upstream: String,
+    /// The directory holding the fixtures of this endpoint
+    dir: PathBuf,
+    client: reqwest::Client,
+}
+
+impl FixtureProxy {
+    fn new(mode: FixtureMode, upstream: String, dir: PathBuf) -> Self {
+        Self {
+            mode,
+            upstream,
+            dir,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Answers a request from its fixture, recording the fixture first in record mode
+    async fn respond(&self, method: &Method, path: &str, body: &[u8]) -> Result<Fixture> {
+        let request = String::from_utf8_lossy(body).to_string();
+        let context = format!("{} {}", method, path);
+        // the ids are taken out of the key and put back into the response
+        let (key, ids) = match serde_json::from_str::<Value>(&request) {
+            Ok(mut json) => {
+                let ids = replace_ids(&mut json, &mut iter::repeat(Value::Null));
+                (fixture_key(method, path, &json.to_string()), ids)
+            }
+            Err(_) => (fixture_key(method, path, &request), Vec::new()),
+        };
+        let file = self.dir.join(format!("{}.json", key));
+        let mut fixture = match self.mode {
+            FixtureMode::Replay => {
+                let contents = fs::read(&file).map_err(|e| {
+                    CoprocessorError::Fixture(anyhow!("No fixture for {}: {}", context, e))
+                })?;
+                serde_json::from_slice::<Fixture>(&contents)
+                    .map_err(|e| CoprocessorError::decoding(format!("fixture of {}", context), e))?
+            }
+            FixtureMode::Record => {
+                let fixture = self.forward(method, path, request).await?;
+                // failed responses are not recorded, so that a retried request is replayed
+                // with the answer it finally got
+                if StatusCode::from_u16(fixture.status).is_ok_and(|status| status.is_success()) {
+                    fs::create_dir_all(&self.dir)
+                        .and_then(|_| {
+                            fs::write(&file, serde_json::to_vec_pretty(&fixture).unwrap())
+                        })
+                        .map_err(|e| CoprocessorError::Fixture(e.into()))?;
+                }
+                fixture
+            }
+        };
+        if let Ok(mut response) = serde_json::from_str::<Value>(&fixture.response) {
+            if !ids.is_empty() {
+                replace_ids(&mut response, &mut ids.into_iter());
+                fixture.response = response.to_string();
+            }
+        }
+        Ok(fixture)
+    }
+
+    /// Sends a request to the upstream endpoint
+    async fn forward(&self, method: &Method, path: &str, request: String) -> Result<Fixture> {
+        let context = format!("{} {}", method, path);
+        let url = format!("{}{}", self.upstream.trim_end_matches('/'), path);
+        let upstream_method = reqwest::Method::from_bytes(method.as_str().as_bytes())
+            .map_err(|e| CoprocessorError::Fixture(e.into()))?;
+        let mut builder = self.client.request(upstream_method, &url);
+        if !request.is_empty() {
+            builder = builder
+                .header(CONTENT_TYPE.as_str(), "application/json")
+                .body(request.clone());
+        }
+        let response = builder
+            .send()
+            .await
+            .map_err(|e| CoprocessorError::rpc(&context, e))?;
+        let status = response.status().as_u16();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE.as_str())
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let response = response
+            .text()
+            .await
+            .map_err(|e| CoprocessorError::rpc(&context, e))?;
+        Ok(Fixture {
+            method: method.to_string(),
+            path: path.to_string(),
+            request,
+            status,
+            content_type,
+            response,
+        })
+    }
+}
+
+/// Handles every request made to a proxy
+async fn handle(
+    State(proxy): State<Arc<FixtureProxy>>,
+    method: Method,
+    uri: Uri,
+    body: Bytes,
+) -> Response {
+    let path = uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    match proxy.respond(&method, path, &body).await {
+        Ok(fixture) => {
+            let status = StatusCode::from_u16(fixture.status).unwrap_or(StatusCode::BAD_GATEWAY);
+            let mut response = (status, fixture.response).into_response();
+            if let Some(content_type) = fixture
+                .content_type
+                .and_then(|content_type| HeaderValue::from_str(&content_type).ok())
+            {
+                response.headers_mut().insert(CONTENT_TYPE, content_type);
+            }
+            response
+        }
+        Err(error) => (
+            StatusCode::BAD_GATEWAY,
+            format!("{:#}", anyhow::Error::from(error)),
+        )
+            .into_response(),
+    }
+}
+
+/// Serves a proxy on a bound listener until the process exits
+async fn serve(listener: TcpListener, proxy: FixtureProxy) -> Result<()> {
+    let listener = tokio::net::TcpListener::from_std(listener)
+        .map_err(|e| CoprocessorError::Fixture(e.into()))?;
+    let router = Router::new().fallback(handle).with_state(Arc::new(proxy));
+    axum::serve(listener, router)
+        .await
+        .map_err(|e| CoprocessorError::Fixture(e.into()))
+}
+
+/// Binds a listener on a free local port
+///
+/// # Returns
+/// The listener and its url
+fn bind() -> Result<(TcpListener, String)> {
+    let listener =
+        TcpListener::bind("127.0.0.1:0").map_err(|e| CoprocessorError::Fixture(e.into()))?;
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| CoprocessorError::Fixture(e.into()))?;
+    let address = listener
+        .local_addr()
+        .map_err(|e| CoprocessorError::Fixture(e.into()))?;
+    Ok((listener, format!("http://{}", address)))
+}
+
+/// Routes the configured RPC endpoints through fixture proxies if `RPC_FIXTURE_MODE` is set
+///
+/// Only the first endpoint of a comma separated list is recorded. The proxies run on a
+/// dedicated thread, so they outlive the runtime of the caller. The environment is left
+/// untouched, the clients pick up the proxies through [`read_endpoint`]. Calling this again is
+/// a no-op.
+pub fn install() -> Result<()> {
+    static INSTALLED: Mutex<bool> = Mutex::new(false);
+    let mut installed = INSTALLED.lock().unwrap();
+    if *installed {
+        return Ok(());
+    }
+    let Some(mode) = FixtureMode::from_env()? else {
+        return Ok(());
+    };
+    let fixture_dir = read_fixture_dir();
+    let mut routes = HashMap::new();
+    let mut proxies = Vec::new();
+    for (variable, name) in routed_endpoints() {
+        let Some(upstream) = env::var(&variable)
+            .ok()
+            .and_then(|urls| parse_endpoints(&urls).into_iter().next())
+        else {
+            continue;
+        };
+        let (listener, url) = bind()?;
+        proxies.push((
+            listener,
+            FixtureProxy::new(mode, upstream, fixture_dir.join(&name)),
+        ));
+        routes.insert(variable, Some(url));
+    }
+    for variable in websocket_endpoints() {
+        routes.insert(variable, None);
+    }
+    thread::Builder::new()
+        .name("rpc-fixtures".to_string())
+        .spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().expect("Failed to start fixture runtime");
+            runtime.block_on(futures::future::join_all(
+                proxies
+                    .into_iter()
+                    .map(|(listener, proxy)| serve(listener, proxy)),
+            ));
+        })
+        .map_err(|e| CoprocessorError::Fixture(e.into()))?;
+    ROUTES
+        .set(routes)
+        .map_err(|_| CoprocessorError::Fixture(anyhow!("Fixture routes are already set")))?;
+    *installed = true;
+    Ok(())
+}
+
+/// Reads the fixture directory from environment variables
+///
+/// # Returns
+/// The value of `RPC_FIXTURE_DIR`, `fixtures` if unset
+fn read_fixture_dir() -> PathBuf {
+    dotenv().ok();
+    PathBuf::from(env::var("RPC_FIXTURE_DIR").unwrap_or_else(|_| "fixtures".to_string()))
+}
+
+/// The file name of the fixture of a request
+fn fixture_key(method: &Method, path: &str, request: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_str());
+    hasher.update(b"\n");
+    hasher.update(path);
+    hasher.update(b"\n");
+    hasher.update(request);
+    hex::encode(hasher.finalize())
+}
+
+/// Replaces the ids of a JSON-RPC message or batch in order
+///
+/// # Returns
+/// The previous ids
+fn replace_ids(value: &mut Value, ids: &mut impl Iterator<Item = Value>) -> Vec<Value> {
+    match value {
+        Value::Array(batch) => batch
+            .iter_mut()
+            .flat_map(|message| replace_ids(message, ids))
+            .collect(),
+        Value::Object(message) if message.contains_key("jsonrpc") => ids
+            .next()
+            .map(|id| message.insert("id".to_string(), id).unwrap_or(Value::Null))
+            .into_iter()
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FixtureMode, FixtureProxy, bind, serve};
+    use alloy::transports::http::reqwest;
+    use axum::{Json, Router, extract::State, http::StatusCode};
+    use serde_json::{Value, json};
+    use std::{
+        future::IntoFuture,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    /// Answers every request with the number of requests answered before it
+    async fn count(
+        State(calls): State<Arc<AtomicUsize>>,
+        Json(request): Json<Value>,
+    ) -> Json<Value> {
+        Json(json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": calls.fetch_add(1, Ordering::SeqCst),
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let fixture_dir = tempfile::tempdir().unwrap();
+        let (listener, upstream_url) = bind().unwrap();
+        let upstream = Router::new()
+            .fallback(count)
+            .with_state(Arc::new(AtomicUsize::new(0)));
+        tokio::spawn(
+            axum::serve(
+                tokio::net::TcpListener::from_std(listener).unwrap(),
+                upstream,
+            )
+            .into_future(),
+        );
+        let (listener, recorder_url) = bind().unwrap();
+        tokio::spawn(serve(
+            listener,
+            FixtureProxy::new(
+                FixtureMode::Record,
+                upstream_url,
+                fixture_dir.path().join("test"),
+            ),
+        ));
+        // the replaying proxy has no reachable upstream
+        let (listener, replayer_url) = bind().unwrap();
+        tokio::spawn(serve(
+            listener,
+            FixtureProxy::new(
+                FixtureMode::Replay,
+                "http://127.0.0.1:9".to_string(),
+                fixture_dir.path().join("test"),
+            ),
+        ));
+
+        let client = reqwest::Client::new();
+        let request = |url: &str, method: &str, id: u64| {
+            client
+                .post(url)
+                .json(&json!({"jsonrpc": "2.0", "id": id, "method": method, "params": []}))
+                .send()
+        };
+        let recorded: Value = request(&recorder_url, "status", 1)
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(recorded, json!({"jsonrpc": "2.0", "id": 1, "result": 0}));
+        // the replayed response carries the id of the replayed request
+        let replayed: Value = request(&replayer_url, "status", 7)
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(replayed, json!({"jsonrpc": "2.0", "id": 7, "result": 0}));
+        // requests that were never recorded fail
+        let missing = request(&replayer_url, "block", 8).await.unwrap();
+        assert_eq!(missing.status().as_u16(), StatusCode::BAD_GATEWAY.as_u16());
+    }
+}
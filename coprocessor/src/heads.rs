@@ -0,0 +1,187 @@
+//! Subscriptions to new finalized heights.
+//!
+//! A long-running coprocessor follows the finalized heights of every domain and proves an update
+//! once a domain advanced far enough. New heads are announced by a websocket subscription where
+//! one is configured, and polled otherwise or once the subscription fails.
+
+use std::{collections::BTreeMap, env, future::ready, time::Duration};
+
+use dotenvy::dotenv;
+use futures::{
+    StreamExt,
+    future::BoxFuture,
+    stream::{self, BoxStream},
+};
+
+use crate::error::Result;
+
+/// A stream of strictly increasing finalized heights
+pub type HeightStream = BoxStream<'static, Result<u64>>;
+
+/// Turns new-head notifications into a stream of strictly increasing finalized heights
+///
+/// The current height is queried right away and again after every notification, and emitted
+/// whenever it advanced. Once the notifications end or fail, the height is polled every
+/// `poll_interval` instead. Failed queries are emitted as errors and do not end the stream.
+///
+/// # Arguments
+/// * `notifications` - Announcements of new heads, `None` to poll from the start
+/// * `poll_interval` - The delay between two queries when polling
+/// * `fetch` - Queries the current finalized height
+pub fn finalized_heights<F>(
+    notifications: Option<BoxStream<'static, Result<()>>>,
+    poll_interval: Duration,
+    fetch: F,
+) -> HeightStream
+where
+    F: Fn() -> BoxFuture<'static, Result<u64>> + Send + 'static,
+{
+    let polling = stream::unfold(
+        tokio::time::interval(poll_interval),
+        |mut interval| async move {
+            interval.tick().await;
+            Some(((), interval))
+        },
+    );
+    let ticks = match notifications {
+        Some(notifications) => notifications
+            .scan((), |_, notification| {
+                ready(match notification {
+                    Ok(()) => Some(()),
+                    Err(e) => {
+                        println!("Head subscription failed, polling instead: {}", e);
+                        None
+                    }
+                })
+            })
+            .chain(polling)
+            .boxed(),
+        None => polling.boxed(),
+    };
+    stream::once(ready(()))
+        .chain(ticks)
+        .then(move |_| fetch())
+        .scan(None, |last: &mut Option<u64>, height| {
+            let next = match height {
+                // heights that did not advance are skipped, not the end of the stream
+                Ok(height) if last.is_some_and(|last| height <= last) => None,
+                Ok(height) => {
+                    *last = Some(height);
+                    Some(Ok(height))
+                }
+                Err(e) => Some(Err(e)),
+            };
+            ready(Some(next))
+        })
+        .filter_map(ready)
+        .boxed()
+}
+
+/// Decides when a coprocessor update is worth proving
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdatePolicy {
+    /// The number of blocks each domain has to advance by before it triggers an update,
+    /// keyed by domain id
+    pub min_blocks: BTreeMap<String, u64>,
+}
+
+impl UpdatePolicy {
+    /// Reads the minimum advance of every domain from `WATCH_MIN_<DOMAIN>_BLOCKS`
+    ///
+    /// # Arguments
+    /// * `defaults` - The domain ids with the minimum advance used if the variable is unset
+    pub fn from_env(defaults: &[(&str, u64)]) -> Self {
+        dotenv().ok();
+        let min_blocks = defaults
+            .iter()
+            .map(|(domain_id, default)| {
+                let variable = format!("WATCH_MIN_{}_BLOCKS", domain_id.to_uppercase());
+                let min_blocks = env::var(&variable)
+                    .map(|value| {
+                        value
+                            .parse()
+                            .unwrap_or_else(|_| panic!("Invalid {}!", variable))
+                    })
+                    .unwrap_or(*default);
+                (domain_id.to_string(), min_blocks)
+            })
+            .collect();
+        Self { min_blocks }
+    }
+
+    /// Whether any domain advanced far enough since the last update
+    ///
+    /// # Arguments
+    /// * `proven` - The height of each domain at the last update
+    /// * `latest` - The latest finalized height of each domain
+    pub fn is_due(&self, proven: &BTreeMap<String, u64>, latest: &BTreeMap<String, u64>) -> bool {
+        self.min_blocks.iter().any(|(domain_id, min_blocks)| {
+            match (proven.get(domain_id), latest.get(domain_id)) {
+                (Some(proven), Some(latest)) => latest.saturating_sub(*proven) >= *min_blocks,
+                _ => false,
+            }
+        })
+    }
+}
+
+/// Reads whether the coprocessor keeps running and proves updates as new blocks are finalized
+///
+/// # Returns
+/// The value of `COPROCESSOR_WATCH`, `false` if unset
+pub fn read_watch_mode() -> bool {
+    dotenv().ok();
+    env::var("COPROCESSOR_WATCH").is_ok_and(|watch| watch == "true" || watch == "1")
+}
+
+/// Reads the delay between two height queries when no subscription is available
+///
+/// # Returns
+/// The value of `HEAD_POLL_INTERVAL_SECS`, 12 seconds if unset
+pub fn read_head_poll_interval() -> Duration {
+    dotenv().ok();
+    Duration::from_secs(
+        env::var("HEAD_POLL_INTERVAL_SECS")
+            .map(|value| value.parse().expect("Invalid HEAD_POLL_INTERVAL_SECS!"))
+            .unwrap_or(12),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::finalized_heights;
+    use crate::error::CoprocessorError;
+    use anyhow::anyhow;
+    use futures::{FutureExt, StreamExt, stream};
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn test_heights_fall_back_to_polling() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let heights = [10, 10, 11, 12, 12, 13];
+        let notifications = stream::iter([
+            Ok(()),
+            Ok(()),
+            Err(CoprocessorError::rpc("test", anyhow!("closed"))),
+            // never reached, the stream is abandoned after the first error
+            Ok(()),
+        ])
+        .boxed();
+        let stream = finalized_heights(Some(notifications), Duration::from_millis(1), {
+            let queries = queries.clone();
+            move || {
+                let query = queries.fetch_add(1, Ordering::SeqCst);
+                async move { Ok(heights[query]) }.boxed()
+            }
+        });
+        let emitted: Vec<u64> = stream.take(4).map(Result::unwrap).collect().await;
+        // heights that did not advance are skipped
+        assert_eq!(emitted, vec![10, 11, 12, 13]);
+        assert_eq!(queries.load(Ordering::SeqCst), 6);
+    }
+}
@@ -1,17 +1,54 @@
-use anyhow::Result;
-use helios_consensus_core::consensus_spec::MainnetConsensusSpec;
+use alloy::dyn_abi::SolType;
+use alloy_primitives::U256;
+use anyhow::{Context, anyhow};
+use coprocessor_circuit_types::{LightClientProof, ProofMode, TrustedState};
+use dotenvy::dotenv;
+use helios_consensus_core::{
+    apply_finality_update, apply_update,
+    consensus_spec::MainnetConsensusSpec,
+    types::{FinalityUpdate, LightClientStore},
+    verify_finality_update, verify_update,
+};
+use helios_ethereum::config::{Config, networks::Network};
 use helios_ethereum::consensus::Inner;
 use helios_ethereum::rpc::ConsensusRpc;
 use helios_ethereum::rpc::http_rpc::HttpRpc;
-use helios_operator::{get_checkpoint, get_client, get_updates};
-use sp1_helios_primitives::types::ProofInputs;
+use sp1_helios_primitives::types::{ProofInputs, ProofOutputs};
 use sp1_sdk::{
     EnvProver, HashableKey, ProverClient, SP1ProofWithPublicValues, SP1ProvingKey, SP1Stdin,
     SP1VerifyingKey,
 };
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::{mpsc::channel, watch};
+use tree_hash::TreeHash;
+
+use super::LightClientUpdate;
+use crate::{
+    domains::Domain,
+    error::{CoprocessorError, Result},
+    read_coprocessor_data_dir, read_ethereum_consensus_rpc_url,
+};
 
 pub const ELF: &[u8] = include_bytes!("../../../elfs/sp1-helios-elf");
 
+/// Slots in a sync committee period, 256 epochs of 32 slots
+const SLOTS_PER_SYNC_COMMITTEE_PERIOD: u64 = 8192;
+/// Maximum number of light client updates a beacon node serves per request, which bounds the
+/// number of sync committee periods a single proof can cover
+const MAX_LIGHT_CLIENT_UPDATES: u64 = 128;
+
+/// A Helios proof together with the light client store it advances to
+pub struct HeliosUpdate {
+    pub proof: SP1ProofWithPublicValues,
+    /// The store after applying the proven updates, holding the finalized header and the
+    /// current and next sync committee of the new head
+    pub store: LightClientStore<MainnetConsensusSpec>,
+}
+
 pub struct SP1HeliosOperator {
     pub client: EnvProver,
     pub pk: SP1ProvingKey,
@@ -30,11 +67,18 @@ impl SP1HeliosOperator {
     async fn request_update(
         &self,
         client: Inner<MainnetConsensusSpec, HttpRpc>,
+        finality_update: FinalityUpdate<MainnetConsensusSpec>,
         update_count: u8,
-    ) -> Result<Option<SP1ProofWithPublicValues>> {
+    ) -> Result<Option<HeliosUpdate>> {
         let mut stdin = SP1Stdin::new();
-        let updates = get_updates(&client, update_count).await;
-        let finality_update = client.rpc.get_finality_update().await.unwrap();
+        let period = client.store.finalized_header.beacon().slot / SLOTS_PER_SYNC_COMMITTEE_PERIOD;
+        let updates = client
+            .rpc
+            .get_updates(period, update_count)
+            .await
+            .map_err(|e| {
+                CoprocessorError::rpc("Helios sync committee updates", anyhow!("{}", e))
+            })?;
         // Create program inputs
         let expected_current_slot = client.expected_current_slot();
         let inputs = ProofInputs {
@@ -45,25 +89,47 @@ impl SP1HeliosOperator {
             genesis_root: client.config.chain.genesis_root,
             forks: client.config.forks.clone(),
         };
-        let encoded_proof_inputs = serde_cbor::to_vec(&inputs)?;
+        // apply the updates to our copy of the store, as the program does to its own copy
+        let store = advance_store(&inputs)?;
+        let encoded_proof_inputs = serde_cbor::to_vec(&inputs)
+            .map_err(|e| CoprocessorError::decoding("Helios proof inputs", e))?;
         stdin.write_slice(&encoded_proof_inputs);
         // Generate proof.
-        let proof = self.client.prove(&self.pk, &stdin).groth16().run()?;
-        Ok(Some(proof))
+        let proof = self
+            .client
+            .prove(&self.pk, &stdin)
+            .groth16()
+            .run()
+            .map_err(|e| CoprocessorError::proving("Helios light client update", e))?;
+        Ok(Some(HeliosUpdate { proof, store }))
     }
 
-    /// Start the operator.
-    pub async fn run(
-        &mut self,
-        slot: u64,
-        update_count: u8,
-    ) -> Result<Option<SP1ProofWithPublicValues>> {
-        let slot: u64 = slot;
-        let checkpoint = get_checkpoint(slot).await.unwrap();
-        // Get the client from the checkpoint
-        let client = get_client(checkpoint).await.unwrap();
+    /// Proves the transition from `trusted_slot` to the latest finalized head, requesting the
+    /// sync committee updates of every period in between
+    ///
+    /// The client resumes from the store persisted for `trusted_slot` and is only bootstrapped
+    /// from a checkpoint if there is none.
+    ///
+    /// # Returns
+    /// `None` if the latest finalized head is not newer than `trusted_slot`
+    pub async fn run(&mut self, trusted_slot: u64) -> Result<Option<HeliosUpdate>> {
+        let client = match load_store(trusted_slot).map_err(CoprocessorError::Checkpoint)? {
+            Some(store) => resume_client(store)?,
+            None => bootstrap_client(trusted_slot).await?,
+        };
+        let finality_update = client
+            .rpc
+            .get_finality_update()
+            .await
+            .map_err(|e| CoprocessorError::rpc("Helios finality update", anyhow!("{}", e)))?;
+        let finalized_slot = finality_update.finalized_header().beacon().slot;
+        if finalized_slot <= trusted_slot {
+            return Ok(None);
+        }
+        let update_count = sync_committee_updates(trusted_slot, finalized_slot)?;
         // Request an update
-        self.request_update(client, update_count).await
+        self.request_update(client, finality_update, update_count)
+            .await
     }
 
     pub fn get_vk(&self) -> String {
@@ -71,23 +137,278 @@ impl SP1HeliosOperator {
     }
 }
 
+/// Proves the Helios light client update from the trusted slot of a domain to the latest
+/// finalized head
+///
+/// A domain without a trusted slot is bootstrapped from `HELIOS_BOOTSTRAP_SLOT`.
+///
+/// # Returns
+/// `None` if no finalized head newer than the trusted slot is available
+///
+/// # Errors
+/// Fails if the proof does not start at the trusted slot or does not advance past it
+pub async fn prove_update(domain: &Domain) -> Result<Option<LightClientUpdate>> {
+    let trusted_slot = match domain.trusted.height {
+        0 => read_helios_bootstrap_slot()?,
+        slot => slot,
+    };
+    let mut operator = SP1HeliosOperator::new();
+    let Some(HeliosUpdate { proof, store }) = operator.run(trusted_slot).await? else {
+        return Ok(None);
+    };
+    let output: ProofOutputs = ProofOutputs::abi_decode(&proof.public_values.to_vec(), false)
+        .map_err(|e| CoprocessorError::decoding("Helios proof outputs", e))?;
+    let previous = TrustedState {
+        height: slot_to_u64(output.prevHead)?,
+        root: output.prevHeader.to_vec(),
+    };
+    let target = TrustedState {
+        height: slot_to_u64(output.newHead)?,
+        root: output.newHeader.to_vec(),
+    };
+    if previous.height != trusted_slot {
+        return Err(CoprocessorError::Verification(format!(
+            "Helios proof starts at slot {}, expected the trusted slot {}",
+            previous.height, trusted_slot
+        )));
+    }
+    if target.height <= trusted_slot {
+        return Err(CoprocessorError::Verification(format!(
+            "Helios proof head {} is not newer than the trusted slot {}",
+            target.height, trusted_slot
+        )));
+    }
+    if store.finalized_header.beacon().slot != target.height {
+        return Err(CoprocessorError::Verification(format!(
+            "Helios store was advanced to slot {}, the proof to slot {}",
+            store.finalized_header.beacon().slot,
+            target.height
+        )));
+    }
+    // the store of the trusted slot is kept until an update from the new head is proven, so
+    // that a failed coprocessor update can resume from it
+    save_store(&store, trusted_slot).map_err(CoprocessorError::Checkpoint)?;
+    Ok(Some(LightClientUpdate {
+        proofs: vec![LightClientProof {
+            proof_mode: ProofMode::Groth16,
+            proof: proof.bytes(),
+            public_values: proof.public_values.to_vec(),
+            vk: operator.get_vk(),
+        }],
+        previous,
+        target,
+        target_time: None,
+    }))
+}
+
+/// The number of sync committee updates that lead from the trusted slot to the finalized slot
+///
+/// The update of the trusted period is included, since it carries the next sync committee.
+///
+/// # Errors
+/// Fails if the periods in between are more than a single proof can cover
+fn sync_committee_updates(trusted_slot: u64, finalized_slot: u64) -> Result<u8> {
+    let periods = finalized_slot / SLOTS_PER_SYNC_COMMITTEE_PERIOD
+        - trusted_slot / SLOTS_PER_SYNC_COMMITTEE_PERIOD;
+    let updates = periods + 1;
+    if updates > MAX_LIGHT_CLIENT_UPDATES {
+        return Err(CoprocessorError::Config(format!(
+            "Trusted slot {} is {} sync committee periods behind the finalized slot {}, \
+             at most {} can be proven at once",
+            trusted_slot,
+            periods,
+            finalized_slot,
+            MAX_LIGHT_CLIENT_UPDATES - 1
+        )));
+    }
+    Ok(updates as u8)
+}
+
+/// Verifies and applies the sync committee and finality updates of the proof inputs to the
+/// store they start from
+fn advance_store(
+    inputs: &ProofInputs<MainnetConsensusSpec>,
+) -> Result<LightClientStore<MainnetConsensusSpec>> {
+    let mut store = inputs.store.clone();
+    for update in &inputs.updates {
+        verify_update(
+            update,
+            inputs.expected_current_slot,
+            &store,
+            inputs.genesis_root,
+            &inputs.forks,
+        )
+        .map_err(|e| CoprocessorError::Verification(format!("Helios update: {}", e)))?;
+        apply_update(&mut store, update);
+    }
+    verify_finality_update(
+        &inputs.finality_update,
+        inputs.expected_current_slot,
+        &store,
+        inputs.genesis_root,
+        &inputs.forks,
+    )
+    .map_err(|e| CoprocessorError::Verification(format!("Helios finality update: {}", e)))?;
+    apply_finality_update(&mut store, &inputs.finality_update);
+    Ok(store)
+}
+
+/// Creates a Helios client for the beacon node of `SOURCE_CONSENSUS_RPC_URL`, without a store
+///
+/// The url is read through the fixture routes, so that recorded runs replay the beacon node too.
+fn new_client() -> Result<Inner<MainnetConsensusSpec, HttpRpc>> {
+    dotenv().ok();
+    let consensus_rpc = read_ethereum_consensus_rpc_url();
+    let chain_id = env::var("SOURCE_CHAIN_ID")
+        .ok()
+        .and_then(|chain_id| chain_id.parse().ok())
+        .ok_or_else(|| CoprocessorError::Config("Missing SOURCE_CHAIN_ID".to_string()))?;
+    let base_config = Network::from_chain_id(chain_id)
+        .map_err(|e| CoprocessorError::Config(format!("Unsupported SOURCE_CHAIN_ID: {}", e)))?
+        .to_base_config();
+    let config = Config {
+        consensus_rpc: consensus_rpc.clone(),
+        execution_rpc: String::new(),
+        chain: base_config.chain,
+        forks: base_config.forks,
+        strict_checkpoint_age: false,
+        ..Default::default()
+    };
+    let (block_send, _) = channel(256);
+    let (finalized_block_send, _) = watch::channel(None);
+    let (checkpoint_send, _) = watch::channel(None);
+    Ok(Inner::<MainnetConsensusSpec, HttpRpc>::new(
+        &consensus_rpc,
+        block_send,
+        finalized_block_send,
+        checkpoint_send,
+        Arc::new(config),
+    ))
+}
+
+/// Creates a Helios client from a persisted store instead of bootstrapping it from a checkpoint
+fn resume_client(
+    store: LightClientStore<MainnetConsensusSpec>,
+) -> Result<Inner<MainnetConsensusSpec, HttpRpc>> {
+    let mut client = new_client()?;
+    client.store = store;
+    Ok(client)
+}
+
+/// Bootstraps a Helios client from the beacon block at `slot`
+async fn bootstrap_client(slot: u64) -> Result<Inner<MainnetConsensusSpec, HttpRpc>> {
+    let mut client = new_client()?;
+    let block = client.rpc.get_block(slot).await.map_err(|e| {
+        CoprocessorError::rpc(
+            format!("Helios checkpoint at slot {}", slot),
+            anyhow!("{}", e),
+        )
+    })?;
+    client
+        .bootstrap(block.tree_hash_root())
+        .await
+        .map_err(|e| CoprocessorError::rpc("Helios client bootstrap", anyhow!("{}", e)))?;
+    Ok(client)
+}
+
+/// The directory holding the persisted Helios stores
+fn store_dir() -> PathBuf {
+    Path::new(&read_coprocessor_data_dir()).join("helios")
+}
+
+/// The file of the store advanced to `slot`
+fn store_path(dir: &Path, slot: u64) -> PathBuf {
+    dir.join(format!("store-{}.cbor", slot))
+}
+
+/// Loads the store advanced to `slot`
+///
+/// # Returns
+/// `None` if no store was persisted for `slot`
+fn load_store(slot: u64) -> anyhow::Result<Option<LightClientStore<MainnetConsensusSpec>>> {
+    let path = store_path(&store_dir(), slot);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let bytes = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let store = serde_cbor::from_slice(&bytes)
+        .with_context(|| format!("Failed to decode Helios store {}", path.display()))?;
+    Ok(Some(store))
+}
+
+/// Persists a store under the slot of its finalized header and removes the stores of slots
+/// before `keep_from`
+fn save_store(
+    store: &LightClientStore<MainnetConsensusSpec>,
+    keep_from: u64,
+) -> anyhow::Result<()> {
+    let dir = store_dir();
+    fs::create_dir_all(&dir)?;
+    let path = store_path(&dir, store.finalized_header.beacon().slot);
+    // write to a temporary file first so that a crash never leaves a partial store behind
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, serde_cbor::to_vec(store)?)?;
+    fs::rename(&temporary, &path)?;
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let slot = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("store-")?.strip_suffix(".cbor"))
+            .and_then(|slot| slot.parse::<u64>().ok());
+        if slot.is_some_and(|slot| slot < keep_from) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the slot the Helios light client is bootstrapped from on the first run
+///
+/// # Returns
+/// The value of `HELIOS_BOOTSTRAP_SLOT`
+fn read_helios_bootstrap_slot() -> Result<u64> {
+    dotenv().ok();
+    env::var("HELIOS_BOOTSTRAP_SLOT")
+        .map_err(|_| {
+            CoprocessorError::Config(
+                "Ethereum has no trusted slot yet, set HELIOS_BOOTSTRAP_SLOT".to_string(),
+            )
+        })?
+        .parse()
+        .map_err(|_| CoprocessorError::Config("Invalid HELIOS_BOOTSTRAP_SLOT".to_string()))
+}
+
+/// Converts a slot from the Helios proof outputs
+fn slot_to_u64(slot: U256) -> Result<u64> {
+    slot.try_into().map_err(|_| {
+        CoprocessorError::decoding(
+            "Helios proof outputs",
+            anyhow!("Slot {} does not fit u64", slot),
+        )
+    })
+}
+
 #[cfg(test)]
 mod test {
-    use super::SP1HeliosOperator;
+    use super::{SLOTS_PER_SYNC_COMMITTEE_PERIOD, SP1HeliosOperator, sync_committee_updates};
     use sp1_sdk::HashableKey;
     use sp1_verifier::Groth16Verifier;
     use std::time::Instant;
     #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "needs a beacon node or recorded fixtures, see RPC_FIXTURE_MODE"]
     async fn test_helios_prover() {
         let start_time = Instant::now();
         dotenvy::dotenv().ok();
+        crate::fixtures::install().unwrap();
         let mut operator = SP1HeliosOperator::new();
-        // for testing we hardcode the latest finalized slot from /eth/v1/beacon/states/finalized/finality_checkpoints
+        // for testing we hardcode a recent finalized slot from /eth/v1/beacon/states/finalized/finality_checkpoints
         let proof = operator
-            .run(234644 * 32, 1)
+            .run(234644 * 32)
             .await
             .expect("Failed to prove!")
-            .unwrap();
+            .unwrap()
+            .proof;
 
         // verify the light client proof
         let groth16_vk = *sp1_verifier::GROTH16_VK_BYTES;
@@ -102,4 +423,29 @@ mod test {
         let end_time = Instant::now();
         println!("Time taken: {:?}", end_time.duration_since(start_time));
     }
+
+    #[test]
+    fn test_sync_committee_updates() {
+        let period_start = 10 * SLOTS_PER_SYNC_COMMITTEE_PERIOD;
+        // the update of the trusted period is always requested
+        assert_eq!(
+            sync_committee_updates(period_start + 5, period_start + 100).unwrap(),
+            1
+        );
+        assert_eq!(
+            sync_committee_updates(
+                period_start + 5,
+                period_start + 2 * SLOTS_PER_SYNC_COMMITTEE_PERIOD
+            )
+            .unwrap(),
+            3
+        );
+        assert!(
+            sync_committee_updates(
+                period_start,
+                period_start + 200 * SLOTS_PER_SYNC_COMMITTEE_PERIOD
+            )
+            .is_err()
+        );
+    }
 }
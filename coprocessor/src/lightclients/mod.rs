@@ -1,5 +1,40 @@
+use coprocessor_circuit_types::{LightClientProof, TrustedState};
+
+use crate::{
+    coprocessor::Coprocessor,
+    domains::{Domain, LightClientConfig},
+    error::Result,
+};
+
 pub mod helios;
 pub mod tendermint;
 
-// todo: instantiate the SMT and insert the domain roots. Then prove the coprocessor circuit and verify the deterministic keys.
-// Then parse the coprocessor output and use it to verify the domain state proofs in the application circuit.
+/// The zk light client proofs of a domain together with the state transition they prove
+pub struct LightClientUpdate {
+    /// The proofs, each one starting from the target of the one before
+    pub proofs: Vec<LightClientProof>,
+    /// The trusted state the update starts from
+    pub previous: TrustedState,
+    /// The new state proven by the update
+    pub target: TrustedState,
+    /// Unix time in seconds of the target header, `None` if the light client has no trusting
+    /// period
+    pub target_time: Option<u64>,
+}
+
+/// Proves the next light client update of a domain with the light client of its kind
+///
+/// # Returns
+/// `None` if the domain has no head newer than its trusted state, it then sits out the update
+pub async fn prove_domain_update(
+    coprocessor: &Coprocessor,
+    domain: &Domain,
+) -> Result<Option<LightClientUpdate>> {
+    match &domain.light_client {
+        LightClientConfig::Tendermint(_) => {
+            let client = &coprocessor.tendermint_coprocessor(&domain.id)?.client;
+            tendermint::prove_update(domain, client).await
+        }
+        LightClientConfig::Helios => helios::prove_update(domain).await,
+    }
+}
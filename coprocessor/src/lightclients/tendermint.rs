@@ -1,90 +1,493 @@
-use sp1_sdk::{HashableKey, ProverClient, SP1ProofWithPublicValues};
-use tendermint_operator::{TendermintProver, util::TendermintRPCClient};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use coprocessor_circuit_types::{LightClientProof, ProofMode, TrustedState};
+use sp1_sdk::{
+    EnvProver, HashableKey, ProverClient, SP1ProofWithPublicValues, SP1ProvingKey, SP1Stdin,
+    SP1VerifyingKey,
+};
+use tendermint_light_client_verifier::{
+    ProdVerifier, Verdict, Verifier,
+    options::Options,
+    types::{LightBlock, TrustThreshold},
+};
+use tendermint_program_types::TendermintOutput;
+
+use super::LightClientUpdate;
+use crate::{
+    clients::TendermintClient,
+    domains::{Domain, Reanchor, TendermintChainConfig, unix_now},
+    error::{CoprocessorError, Result},
+};
 
 pub const TENDERMINT_ELF: &[u8] = include_bytes!("../../../elfs/tendermint-elf");
 
+/// The delay after the target block time at which the tendermint program verifies a skip
+const VERIFICATION_DELAY: Duration = Duration::from_secs(20);
+
+/// Proves Tendermint light client updates of a single chain
+///
+/// Everything the operator needs is taken from the client of the chain, so operators of
+/// different chains can be used side by side in one process.
 pub struct SP1TendermintOperator {
-    pub trusted_height: u64,
-    pub target_height: u64,
+    /// The client of the chain the light blocks are fetched from
+    pub tendermint_client: TendermintClient,
+    pub client: EnvProver,
+    pub pk: SP1ProvingKey,
+    pub vk: SP1VerifyingKey,
 }
 
 impl SP1TendermintOperator {
-    pub fn new(trusted_height: u64, target_height: u64) -> Self {
+    pub fn new(tendermint_client: TendermintClient) -> Self {
+        let client = ProverClient::from_env();
+        let (pk, vk) = client.setup(TENDERMINT_ELF);
         Self {
-            trusted_height,
-            target_height,
+            tendermint_client,
+            client,
+            pk,
+            vk,
+        }
+    }
+
+    /// The configuration of the chain
+    fn config(&self) -> &TendermintChainConfig {
+        &self.tendermint_client.config
+    }
+
+    /// Proves the transition from the trusted height to the target height
+    ///
+    /// A single skip is proven if enough of the trusted validator set signed the target block.
+    /// Otherwise the range is bisected into intermediate light blocks and every step is proven
+    /// on its own, each proof starting from the block the one before ended at.
+    ///
+    /// # Returns
+    /// The proofs of every step and the unix time in seconds of the target header
+    ///
+    /// # Errors
+    /// Fails if a light block cannot be fetched or belongs to another chain, or if a step
+    /// cannot be verified, e.g. because its trusted block is outside the trusting period
+    pub async fn prove(
+        &self,
+        trusted_height: u64,
+        target_height: u64,
+    ) -> Result<(Vec<SP1ProofWithPublicValues>, u64)> {
+        let steps = self.bisect(trusted_height, target_height).await?;
+        if steps.len() > 1 {
+            println!(
+                "Proving {} {} blocks from {} to {} in {} steps",
+                self.config().domain_id,
+                target_height - trusted_height,
+                trusted_height,
+                target_height,
+                steps.len()
+            );
+        }
+        let mut proofs = Vec::with_capacity(steps.len());
+        let mut target_time = 0;
+        for (trusted_light_block, target_light_block) in &steps {
+            proofs.push(self.prove_step(trusted_light_block, target_light_block)?);
+            target_time = u64::try_from(target_light_block.time().unix_timestamp()).unwrap_or(0);
         }
+        Ok((proofs, target_time))
+    }
+
+    /// Splits the range from the trusted height to the target height into steps the tendermint
+    /// program accepts, see [`plan_steps`]
+    ///
+    /// The light blocks are fetched through the client of the chain, which retries failing
+    /// endpoints and caches the blocks.
+    async fn bisect(
+        &self,
+        trusted_height: u64,
+        target_height: u64,
+    ) -> Result<Vec<(LightBlock, LightBlock)>> {
+        let client = &self.tendermint_client;
+        plan_steps(
+            &self.config().domain_id,
+            client.get_light_block(trusted_height).await?,
+            client.get_light_block(target_height).await?,
+            |light_block| light_block.height().value(),
+            |height| client.get_light_block(height),
+            |trusted, untrusted| self.verify_step(trusted, untrusted),
+        )
+        .await
     }
-    pub async fn run(&self) -> SP1ProofWithPublicValues {
-        // Instantiate a Tendermint prover based on the environment variable.
-        let tendermint_rpc_client = TendermintRPCClient::default();
-        let prover = TendermintProver::new();
-        let trusted_block_height: u64 = self.trusted_height;
-        //let chain_latest_block_height = tendermint_rpc_client.get_latest_block_height().await;
-        let (trusted_light_block, target_light_block) = tendermint_rpc_client
-            .get_light_blocks(trusted_block_height, self.target_height)
-            .await;
-        // Generate a proof of the transition from the trusted block to the target block.
-        prover.generate_tendermint_proof(&trusted_light_block, &target_light_block)
+
+    /// Verifies a skip the way the tendermint program does, at the time it verifies it
+    ///
+    /// # Errors
+    /// Fails if the trusted block is outside the trusting period at the time of the target
+    /// block
+    fn verify_step(&self, trusted: &LightBlock, untrusted: &LightBlock) -> Result<Verdict> {
+        let expiry = trusted
+            .time()
+            .checked_add(self.config().trusting_period)
+            .ok_or_else(|| {
+                CoprocessorError::Config(format!(
+                    "Invalid trusting period of {}",
+                    self.config().domain_id
+                ))
+            })?;
+        if untrusted.time() >= expiry {
+            return Err(CoprocessorError::Verification(format!(
+                "Trusted {} block {} expired at {}",
+                self.config().domain_id,
+                trusted.height(),
+                expiry
+            )));
+        }
+        let options = Options {
+            trust_threshold: TrustThreshold::ONE_THIRD,
+            trusting_period: self.config().trusting_period,
+            clock_drift: Duration::default(),
+        };
+        let now = untrusted
+            .time()
+            .checked_add(VERIFICATION_DELAY)
+            .ok_or_else(|| {
+                CoprocessorError::decoding(
+                    "Tendermint block time",
+                    anyhow!("{} overflows", untrusted.time()),
+                )
+            })?;
+        Ok(ProdVerifier::default().verify_update_header(
+            untrusted.as_untrusted_state(),
+            trusted.as_trusted_state(),
+            &options,
+            now,
+        ))
+    }
+
+    /// Proves a single skip from the trusted light block to the target light block
+    fn prove_step(
+        &self,
+        trusted_light_block: &LightBlock,
+        target_light_block: &LightBlock,
+    ) -> Result<SP1ProofWithPublicValues> {
+        // the program takes the cbor encoded trusted and target light blocks
+        let mut stdin = SP1Stdin::new();
+        stdin.write_vec(
+            serde_cbor::to_vec(&(trusted_light_block, target_light_block))
+                .map_err(|e| CoprocessorError::decoding("Tendermint proof inputs", e))?,
+        );
+        let request = self.client.prove(&self.pk, &stdin);
+        let request = match self.config().proof_mode {
+            ProofMode::Groth16 => request.groth16(),
+            ProofMode::Plonk => request.plonk(),
+        };
+        request
+            .run()
+            .map_err(|e| CoprocessorError::proving("Tendermint light client update", e))
     }
 
     pub fn get_vk(&self) -> String {
-        let client = ProverClient::from_env();
-        let (_, vk) = client.setup(TENDERMINT_ELF);
-        vk.bytes32().to_string()
+        self.vk.bytes32()
+    }
+}
+
+/// Splits the range from a trusted block to a target block into steps that verify
+///
+/// A step whose target was signed by too little of the trusted validator set is replaced by two
+/// steps meeting at the block halfway in between, until every step verifies. Adjacent blocks
+/// always verify unless they are invalid.
+///
+/// # Arguments
+/// * `domain_id` - The domain the blocks belong to, for error messages
+/// * `height` - The height of a block
+/// * `fetch` - Fetches the block at a height
+/// * `verify_step` - Verifies a skip from a trusted block to an untrusted one
+///
+/// # Returns
+/// The steps in order, each one starting from the block the one before ended at
+///
+/// # Errors
+/// Fails if a block cannot be fetched, a step is invalid or adjacent blocks lack trust
+async fn plan_steps<B: Clone, F: Future<Output = Result<B>>>(
+    domain_id: &str,
+    mut trusted: B,
+    target: B,
+    height: impl Fn(&B) -> u64,
+    mut fetch: impl FnMut(u64) -> F,
+    mut verify_step: impl FnMut(&B, &B) -> Result<Verdict>,
+) -> Result<Vec<(B, B)>> {
+    let mut steps = Vec::new();
+    // the blocks still to be reached, the next one last
+    let mut pending = vec![target];
+    while let Some(untrusted) = pending.pop() {
+        match verify_step(&trusted, &untrusted)? {
+            Verdict::Success => {
+                steps.push((trusted, untrusted.clone()));
+                trusted = untrusted;
+            }
+            Verdict::NotEnoughTrust(tally) => {
+                let lower = height(&trusted);
+                let upper = height(&untrusted);
+                if upper - lower < 2 {
+                    return Err(CoprocessorError::Verification(format!(
+                        "Adjacent {} blocks {} and {} lack trust: {}",
+                        domain_id, lower, upper, tally
+                    )));
+                }
+                let pivot = lower + (upper - lower) / 2;
+                pending.push(untrusted);
+                pending.push(fetch(pivot).await?);
+            }
+            Verdict::Invalid(e) => {
+                return Err(CoprocessorError::Verification(format!(
+                    "Invalid {} skip from block {} to {}: {:?}",
+                    domain_id,
+                    height(&trusted),
+                    height(&untrusted),
+                    e
+                )));
+            }
+        }
     }
+    Ok(steps)
+}
+
+/// Proves the Tendermint light client update from the trusted height to the target height of a domain
+///
+/// # Returns
+/// `None` if the target height is not above the trusted height
+///
+/// # Arguments
+/// * `client` - The client of the chain, the light blocks are fetched with
+///
+/// # Errors
+/// Fails if the trusted header of the domain expired, a warning is printed once less than a
+/// third of the trusting period is left
+pub async fn prove_update(
+    domain: &Domain,
+    client: &TendermintClient,
+) -> Result<Option<LightClientUpdate>> {
+    let config = &client.config;
+    if domain.target_height <= domain.trusted.height {
+        return Ok(None);
+    }
+    if let Some(trusted_time) = domain.trusted_time {
+        let remaining = config.remaining_trust(trusted_time, unix_now())?;
+        if remaining < config.trusting_period / 3 {
+            println!(
+                "Warning: the trusted {} header at height {} expires in {:?}",
+                domain.id, domain.trusted.height, remaining
+            );
+        }
+    }
+    let operator = SP1TendermintOperator::new(client.clone());
+    let (proofs, target_time) = operator
+        .prove(domain.trusted.height, domain.target_height)
+        .await?;
+    let outputs = proofs
+        .iter()
+        .map(|proof| {
+            serde_json::from_slice::<TendermintOutput>(&proof.public_values.to_vec())
+                .map_err(|e| CoprocessorError::decoding("Tendermint proof outputs", e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let (Some(first), Some(last)) = (outputs.first(), outputs.last()) else {
+        return Err(CoprocessorError::proving(
+            "Tendermint light client update",
+            anyhow!("No block after height {}", domain.trusted.height),
+        ));
+    };
+    Ok(Some(LightClientUpdate {
+        proofs: proofs
+            .iter()
+            .map(|proof| LightClientProof {
+                proof_mode: config.proof_mode,
+                proof: proof.bytes(),
+                public_values: proof.public_values.to_vec(),
+                vk: operator.get_vk(),
+            })
+            .collect(),
+        previous: TrustedState {
+            height: first.trusted_height,
+            root: first.trusted_header_hash.to_vec(),
+        },
+        target: TrustedState {
+            height: last.target_height,
+            root: last.target_header_hash.to_vec(),
+        },
+        target_time: Some(target_time),
+    }))
+}
+
+/// Replaces the trusted state of a Tendermint domain by the approved header of its configuration
+///
+/// Nothing changes if no re-anchor is configured, if it was proven before, or if the domain
+/// already trusts a header at or above the approved height. The re-anchor stays pending until
+/// an update from the approved header is proven, and is then recorded in the checkpoint.
+///
+/// # Errors
+/// Fails if the header at the approved height does not have the approved hash, or if the
+/// approved header itself is outside the trusting period
+pub async fn reanchor(domain: &mut Domain, client: &TendermintClient) -> Result<()> {
+    let Some(anchor) = &client.config.reanchor else {
+        return Ok(());
+    };
+    let applied = domain.reanchors.iter().any(|reanchor| {
+        reanchor.anchor.height == anchor.height && reanchor.anchor.root == anchor.header_hash
+    });
+    if applied || domain.trusted.height >= anchor.height {
+        return Ok(());
+    }
+    let state = client.get_state_at_height(anchor.height).await?;
+    if state.header_hash != anchor.header_hash {
+        return Err(CoprocessorError::Verification(format!(
+            "The {} header at height {} has hash {}, approved was {}",
+            domain.id,
+            anchor.height,
+            hex::encode(&state.header_hash),
+            hex::encode(&anchor.header_hash)
+        )));
+    }
+    let now = unix_now();
+    let anchor_time = u64::try_from(state.time.unix_timestamp()).unwrap_or(0);
+    client.config.remaining_trust(anchor_time, now)?;
+    let anchored = TrustedState {
+        height: state.height,
+        root: state.header_hash,
+    };
+    println!(
+        "Re-anchoring {} from height {} to the approved height {} ({})",
+        domain.id, domain.trusted.height, anchored.height, anchor.approval
+    );
+    domain.pending_reanchor = Some(Reanchor {
+        replaced: domain.trusted.clone(),
+        anchor: anchored.clone(),
+        anchor_time,
+        approval: anchor.approval.clone(),
+        applied_at: now,
+    });
+    domain.trusted = anchored;
+    domain.trusted_time = Some(anchor_time);
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use std::time::Instant;
 
-    use sp1_sdk::{HashableKey, ProverClient};
     use sp1_verifier::Groth16Verifier;
     use tendermint_program_types::TendermintOutput;
 
+    use coprocessor_circuit_types::NEUTRON_DOMAIN_ID;
+
+    use futures::future::ready;
+    use tendermint_light_client_verifier::{
+        Verdict,
+        types::{TrustThreshold, VotingPowerTally},
+    };
+
     use crate::{
-        clients::{ClientInterface, DefaultClient, EthereumClient, NeutronClient},
-        lightclients::tendermint::{SP1TendermintOperator, TENDERMINT_ELF},
-        read_ethereum_rpc_url, read_neutron_rpc_url,
+        clients::{ClientInterface, TendermintClient},
+        error::Result,
+        lightclients::tendermint::{SP1TendermintOperator, plan_steps},
     };
 
+    /// Trusts skips of at most `max_skip` blocks, like a validator set rotating at that pace
+    fn verify_skip(max_skip: u64) -> impl FnMut(&u64, &u64) -> Result<Verdict> {
+        move |trusted, untrusted| {
+            Ok(if untrusted - trusted <= max_skip {
+                Verdict::Success
+            } else {
+                Verdict::NotEnoughTrust(VotingPowerTally {
+                    total: 3,
+                    tallied: 0,
+                    trust_threshold: TrustThreshold::ONE_THIRD,
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plan_steps_splits_into_contiguous_steps() {
+        let steps = plan_steps(
+            "neutron",
+            100,
+            137,
+            |height| *height,
+            |height| ready(Ok(height)),
+            verify_skip(5),
+        )
+        .await
+        .unwrap();
+        assert!(steps.len() > 1);
+        assert_eq!(steps.first().unwrap().0, 100);
+        assert_eq!(steps.last().unwrap().1, 137);
+        for (trusted, target) in &steps {
+            assert!(trusted < target && target - trusted <= 5);
+        }
+        for pair in steps.windows(2) {
+            assert_eq!(pair[0].1, pair[1].0);
+        }
+        // a skip with enough trust is proven in a single step
+        let steps = plan_steps(
+            "neutron",
+            100,
+            137,
+            |height| *height,
+            |height| ready(Ok(height)),
+            verify_skip(100),
+        )
+        .await
+        .unwrap();
+        assert_eq!(steps, vec![(100, 137)]);
+    }
+
+    #[tokio::test]
+    async fn test_plan_steps_rejects_adjacent_blocks_without_trust() {
+        for target in [101, 104] {
+            assert!(
+                plan_steps(
+                    "neutron",
+                    100,
+                    target,
+                    |height| *height,
+                    |height| ready(Ok(height)),
+                    verify_skip(0),
+                )
+                .await
+                .is_err()
+            );
+        }
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "needs Tendermint endpoints or recorded fixtures, see RPC_FIXTURE_MODE"]
     async fn test_tendermint_prover_and_verifier() {
         let start_time = Instant::now();
         dotenvy::dotenv().ok();
-        let default_client = DefaultClient {
-            neutron_client: NeutronClient {
-                rpc_url: read_neutron_rpc_url(),
-            },
-            ethereum_client: EthereumClient {
-                rpc_url: read_ethereum_rpc_url(),
-            },
-        };
-        let target_block_height: u64 = default_client
-            .neutron_client
-            .get_latest_root_and_height()
-            .await
-            .1;
+        crate::fixtures::install().unwrap();
+        let neutron_client = TendermintClient::from_env(NEUTRON_DOMAIN_ID).unwrap();
+        let target_block_height: u64 = neutron_client.get_latest_root_and_height().await.unwrap().1;
         let trusted_block_height: u64 = target_block_height - 10;
-        let operator = SP1TendermintOperator::new(trusted_block_height, target_block_height);
-        let proof = operator.run().await;
+        let operator = SP1TendermintOperator::new(neutron_client.clone());
+        let (proofs, _) = operator
+            .prove(trusted_block_height, target_block_height)
+            .await
+            .unwrap();
 
-        // verify the light client proof
+        // verify the light client proofs, neutron is proven with the default groth16 mode
         let groth16_vk = *sp1_verifier::GROTH16_VK_BYTES;
-        let client = ProverClient::from_env();
-        let (_, vk) = client.setup(TENDERMINT_ELF);
-        Groth16Verifier::verify(
-            &proof.bytes(),
-            &proof.public_values.to_vec(),
-            &vk.bytes32(),
-            groth16_vk,
-        )
-        .unwrap();
-
-        let proof_out: TendermintOutput =
-            serde_json::from_slice(&proof.public_values.to_vec()).unwrap();
-        println!("proof_out: {:?}", proof_out);
+        let mut trusted_height = trusted_block_height;
+        for proof in proofs {
+            Groth16Verifier::verify(
+                &proof.bytes(),
+                &proof.public_values.to_vec(),
+                &operator.get_vk(),
+                groth16_vk,
+            )
+            .unwrap();
+            let proof_out: TendermintOutput =
+                serde_json::from_slice(&proof.public_values.to_vec()).unwrap();
+            println!("proof_out: {:?}", proof_out);
+            // every step starts where the one before ended
+            assert_eq!(proof_out.trusted_height, trusted_height);
+            trusted_height = proof_out.target_height;
+        }
+        assert_eq!(trusted_height, target_block_height);
         let end_time = Instant::now();
         println!("Time taken: {:?}", end_time.duration_since(start_time));
     }
@@ -1,140 +1,268 @@
-use alloy::transports::http::reqwest;
-use constants::{ETHEREUM_HEIGHT_KEY, ETHEREUM_ROOT_KEY, NEUTRON_HEIGHT_KEY, NEUTRON_ROOT_KEY};
+use anyhow::anyhow;
+use checkpoint::Checkpoint;
 use coprocessor::Coprocessor;
+use coprocessor_circuit_types::{DomainOpenings, ETHEREUM_DOMAIN_ID, TrustedState};
 use dotenvy::dotenv;
+use error::{CoprocessorError, Result};
 #[cfg(feature = "mailbox")]
 use examples::mailbox;
-use examples::prove_coprocessor;
+use examples::{get_domain_openings, prove_coprocessor};
+mod cache;
+mod checkpoint;
 mod clients;
 mod coprocessor;
+mod domains;
+mod error;
+mod fixtures;
+mod heads;
 mod lightclients;
-use clients::{ClientInterface, DefaultClient, EthereumClient, NeutronClient};
-use serde_json::Value;
-use sha2::{Digest, Sha256};
+mod opstack;
+mod storage;
+mod transport;
+use clients::{ClientInterface, DefaultClient};
+use futures::{StreamExt, stream};
+use heads::{UpdatePolicy, read_watch_mode};
 use sp1_sdk::include_elf;
-mod constants;
-use std::{env, time::Instant};
+use std::{collections::BTreeMap, env, time::Instant};
+use transport::parse_endpoints;
 mod examples;
 pub const COPROCESSOR_CIRCUIT_ELF: &[u8] = include_elf!("coprocessor-circuit-sp1");
 pub const MAILBOX_APPLICATION_CIRCUIT_ELF: &[u8] = include_elf!("zk-mailbox-application");
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<()> {
+    // route the RPC endpoints through the fixture proxies before any client is created
+    fixtures::install()?;
+    let default_client = DefaultClient::from_env()?;
+    let mut coprocessor = Coprocessor::from_env(&default_client)?;
+    let mut tendermint_target_heights = BTreeMap::new();
+    for client in &default_client.tendermint_clients {
+        let height = client.get_latest_root_and_height().await?.1;
+        tendermint_target_heights.insert(client.config.domain_id.clone(), height);
+    }
+    // resume from the last proven state if a checkpoint exists
+    if let Some(checkpoint) =
+        Checkpoint::load(&coprocessor.smt_tree).map_err(CoprocessorError::Checkpoint)?
+    {
+        checkpoint
+            .apply(&mut coprocessor)
+            .map_err(CoprocessorError::Checkpoint)?;
+    }
+    // start the tendermint light clients of new domains (all of them on the first run)
+    // from a recent height, and re-anchor domains to their approved headers
+    for client in &default_client.tendermint_clients {
+        let domain_id = &client.config.domain_id;
+        let domain = coprocessor
+            .domains
+            .get_mut(domain_id)
+            .expect("Tendermint domain is not registered");
+        if domain.trusted != TrustedState::default() {
+            lightclients::tendermint::reanchor(domain, client).await?;
+            continue;
+        }
+        let example_trusted_height: u64 = tendermint_target_heights[domain_id] - 10;
+        let trusted_state = client.get_state_at_height(example_trusted_height).await?;
+        // initialize the trusted height and root of the domain, the light client tracks
+        // header hashes
+        domain.trusted = TrustedState {
+            height: trusted_state.height,
+            root: trusted_state.header_hash,
+        };
+        domain.trusted_time = u64::try_from(trusted_state.time.unix_timestamp()).ok();
+    }
+    if read_watch_mode() {
+        watch(&default_client, &mut coprocessor).await
+    } else {
+        update(&mut coprocessor, &tendermint_target_heights).await
+    }
+}
+
+/// Proves a coprocessor update up to the target heights of the Tendermint domains, then proves
+/// the applications against it
+///
+/// # Arguments
+/// * `tendermint_target_heights` - The height each Tendermint domain is updated to, keyed by
+///   domain id
+async fn update(
+    coprocessor: &mut Coprocessor,
+    tendermint_target_heights: &BTreeMap<String, u64>,
+) -> Result<()> {
     let start_time = Instant::now();
-    let mut coprocessor = Coprocessor::from_env();
-    let default_client = DefaultClient {
-        neutron_client: NeutronClient {
-            rpc_url: read_neutron_rpc_url(),
-        },
-        ethereum_client: EthereumClient {
-            rpc_url: read_ethereum_rpc_url(),
-        },
-    };
-    let neutron_target_block_height: u64 = default_client
-        .neutron_client
-        .get_latest_root_and_height()
-        .await
-        .1;
-    let neutron_example_trusted_height: u64 = neutron_target_block_height - 10;
-    coprocessor.target_neutron_height = neutron_target_block_height;
-    coprocessor.trusted_neutron_height = neutron_example_trusted_height;
-    let neutron_trusted_root = default_client
-        .neutron_client
-        .get_state_at_height(neutron_example_trusted_height)
-        .await
-        .0;
-    // initialize the trusted root for neutron
-    coprocessor.trusted_neutron_root = neutron_trusted_root;
+    for (domain_id, target_height) in tendermint_target_heights {
+        coprocessor
+            .domains
+            .get_mut(domain_id)
+            .expect("Tendermint domain is not registered")
+            .target_height = *target_height;
+    }
     // compute the coprocessor update
-    let coprocessor_outputs = prove_coprocessor(&mut coprocessor).await;
-    let neutron_header = default_client
-        .neutron_client
-        .get_header_at_height(coprocessor_outputs.0.target_height)
-        .await;
-    // pass the headers and proof outputs to the application circuit
-    let coprocessor_smt_root = coprocessor.smt_root;
-
-    let mut hasher = Sha256::new();
-    hasher.update(NEUTRON_HEIGHT_KEY);
-    let neutron_height_key = hasher.finalize();
-    let mut hasher = Sha256::new();
-    hasher.update(ETHEREUM_HEIGHT_KEY);
-    let ethereum_height_key = hasher.finalize();
-    let mut hasher = Sha256::new();
-    hasher.update(NEUTRON_ROOT_KEY);
-    let neutron_root_key = hasher.finalize();
-    let mut hasher = Sha256::new();
-    hasher.update(ETHEREUM_ROOT_KEY);
-    let ethereum_root_key = hasher.finalize();
-
-    let neutron_height_opening = coprocessor
-        .smt_tree
-        .get_opening("demo", coprocessor_smt_root, &neutron_height_key)
-        .expect("Failed to get neutron height opening")
-        .unwrap();
-    let ethereum_height_opening = coprocessor
-        .smt_tree
-        .get_opening("demo", coprocessor_smt_root, &ethereum_height_key)
-        .expect("Failed to get ethereum height opening")
-        .unwrap();
-    let neutron_root_opening = coprocessor
+    let coprocessor_outputs = prove_coprocessor(coprocessor).await?;
+    println!(
+        "Coprocessor root {} -> {}",
+        hex::encode(coprocessor_outputs.previous_coprocessor_root),
+        hex::encode(coprocessor_outputs.coprocessor_root)
+    );
+    // applications prove against the root the destination chain has on record,
+    // which may be an older archived root than the one we just committed
+    let application_root = read_application_coprocessor_root().unwrap_or(coprocessor.smt_root);
+    if coprocessor
         .smt_tree
-        .get_opening("demo", coprocessor_smt_root, &neutron_root_key)
-        .expect("Failed to get neutron root opening")
-        .unwrap();
-    let ethereum_root_opening = coprocessor
-        .smt_tree
-        .get_opening("demo", coprocessor_smt_root, &ethereum_root_key)
-        .expect("Failed to get ethereum root opening")
-        .unwrap();
+        .get_archived_root(application_root)
+        .map_err(CoprocessorError::Smt)?
+        .is_none()
+    {
+        return Err(CoprocessorError::Smt(anyhow!(
+            "Application root was never committed"
+        )));
+    }
+    // domains without state under the application root are left out, the applications fail
+    // on missing openings of the domains they read
+    let domain_openings: Vec<DomainOpenings> = coprocessor
+        .domains
+        .iter()
+        .filter_map(|domain| get_domain_openings(coprocessor, domain, application_root).transpose())
+        .collect::<Result<_>>()?;
     // now pass the smt openings to the applications
     #[cfg(feature = "mailbox")]
-    mailbox::prove(
-        neutron_height_opening,
-        ethereum_height_opening,
-        neutron_root_opening,
-        ethereum_root_opening,
-        neutron_header,
-    )
-    .await;
+    mailbox::prove(coprocessor, application_root, domain_openings).await?;
     let end_time = Instant::now();
     println!("Time taken: {:?}", end_time.duration_since(start_time));
+    Ok(())
 }
 
-pub async fn get_execution_block_height(
-    beacon_node_url: &str,
-    slot: u64,
-) -> Result<u64, Box<dyn std::error::Error>> {
-    let url = format!("{}/eth/v2/beacon/blocks/{}", beacon_node_url, slot);
-    let client = reqwest::Client::new();
-    let res = client.get(&url).send().await?.error_for_status()?;
-    let json: Value = res.json().await?;
-    let block_number = json["data"]["message"]["body"]["execution_payload"]["block_number"]
-        .as_str()
-        .ok_or("Missing block_number")?;
-    let block_number = block_number.parse::<u64>()?;
-    Ok(block_number)
+/// Follows the finalized heights of every domain and proves an update whenever one of them
+/// advanced far enough since the last update
+///
+/// Domains without a new head are left out of an update. A failed update is discarded and
+/// retried with the next finalized height.
+async fn watch(default_client: &DefaultClient, coprocessor: &mut Coprocessor) -> Result<()> {
+    let tendermint_domain_ids: Vec<&str> = default_client
+        .tendermint_clients
+        .iter()
+        .map(|client| client.config.domain_id.as_str())
+        .collect();
+    let policy = UpdatePolicy::from_env(
+        &tendermint_domain_ids
+            .iter()
+            .map(|domain_id| (*domain_id, 100))
+            .chain([(ETHEREUM_DOMAIN_ID, 64)])
+            .collect::<Vec<_>>(),
+    );
+    let mut heights = stream::select_all(
+        default_client
+            .tendermint_clients
+            .iter()
+            .map(|client| {
+                let domain_id = client.config.domain_id.clone();
+                client
+                    .finalized_heights()
+                    .map(move |height| (domain_id.clone(), height))
+                    .boxed()
+            })
+            .chain([default_client
+                .ethereum_client
+                .finalized_heights()
+                .map(|height| (ETHEREUM_DOMAIN_ID.to_string(), height))
+                .boxed()]),
+    );
+    // Tendermint domains advance from their trusted height, Ethereum from the first height we see
+    let mut proven: BTreeMap<String, u64> = tendermint_domain_ids
+        .iter()
+        .map(|domain_id| {
+            let domain = coprocessor
+                .domains
+                .get(domain_id)
+                .expect("Tendermint domain is not registered");
+            (domain_id.to_string(), domain.trusted.height)
+        })
+        .collect();
+    let mut latest = BTreeMap::new();
+    while let Some((domain_id, height)) = heights.next().await {
+        let height = match height {
+            Ok(height) => height,
+            Err(e) => {
+                println!("Failed to query the {} height: {:?}", domain_id, e);
+                continue;
+            }
+        };
+        latest.insert(domain_id.clone(), height);
+        proven.entry(domain_id).or_insert(height);
+        // tendermint domains without a known height keep their target and sit out the update
+        let tendermint_target_heights: BTreeMap<String, u64> = latest
+            .iter()
+            .filter(|(domain_id, _)| tendermint_domain_ids.contains(&domain_id.as_str()))
+            .map(|(domain_id, height)| (domain_id.clone(), *height))
+            .collect();
+        if !policy.is_due(&proven, &latest) {
+            continue;
+        }
+        match update(coprocessor, &tendermint_target_heights).await {
+            Ok(()) => proven = latest.clone(),
+            Err(e) => {
+                println!("Coprocessor update failed: {:?}", e);
+                // the next update starts over from the last committed root
+                coprocessor.smt_root = coprocessor.smt_tree.discard_pending();
+            }
+        }
+    }
+    Ok(())
 }
 
-/// Reads the Neutron RPC URL from environment variables
+/// Reads the Ethereum RPC URLs from environment variables
 ///
 /// # Returns
-/// The Neutron RPC URL as a String
-pub(crate) fn read_neutron_rpc_url() -> String {
-    dotenvy::dotenv().ok();
-    env::var("NEUTRON_RPC").expect("Missing Neutron RPC url!")
+/// The comma separated Ethereum RPC URLs, in the order they are tried
+pub(crate) fn read_ethereum_rpc_urls() -> Vec<String> {
+    dotenv().ok();
+    parse_endpoints(&fixtures::read_endpoint("ETHEREUM_URL").expect("Missing Sepolia url!"))
 }
 
-/// Reads the Ethereum RPC URL from environment variables
+/// Reads the coprocessor data directory from environment variables
 ///
 /// # Returns
-/// The Ethereum RPC URL as a String
-pub(crate) fn read_ethereum_rpc_url() -> String {
+/// The directory holding the persistent coprocessor state, `data` if unset
+pub(crate) fn read_coprocessor_data_dir() -> String {
     dotenv().ok();
-    env::var("ETHEREUM_URL").expect("Missing Sepolia url!")
+    env::var("COPROCESSOR_DATA_DIR").unwrap_or_else(|_| "data".to_string())
 }
 
+/// Reads the archived coprocessor root applications should prove against
+///
+/// # Returns
+/// The root from `APPLICATION_COPROCESSOR_ROOT`, `None` to use the latest root
+pub(crate) fn read_application_coprocessor_root() -> Option<[u8; 32]> {
+    dotenv().ok();
+    let root = env::var("APPLICATION_COPROCESSOR_ROOT").ok()?;
+    let root = hex::decode(root.trim_start_matches("0x")).expect("Invalid application root");
+    Some(root.try_into().expect("Application root must be 32 bytes"))
+}
+
+/// Reads the number of storage proofs requested concurrently per domain
+///
+/// # Returns
+/// The value of `PROOF_FETCH_CONCURRENCY`, 8 if unset
+pub(crate) fn read_proof_fetch_concurrency() -> usize {
+    dotenv().ok();
+    env::var("PROOF_FETCH_CONCURRENCY")
+        .map(|value| value.parse().expect("Invalid PROOF_FETCH_CONCURRENCY!"))
+        .unwrap_or(8)
+        .max(1)
+}
+
+/// Reads the Ethereum beacon node URL from environment variables
+///
+/// # Returns
+/// The beacon node URL, also used by the Helios light client
 pub(crate) fn read_ethereum_consensus_rpc_url() -> String {
     dotenv().ok();
-    env::var("SOURCE_CONSENSUS_RPC_URL").expect("Missing Consensus url!")
+    fixtures::read_endpoint("SOURCE_CONSENSUS_RPC_URL").expect("Missing Consensus url!")
+}
+
+/// Reads the Ethereum websocket URL from environment variables
+///
+/// # Returns
+/// The value of `ETHEREUM_WS_URL`, `None` to poll for new blocks instead
+pub(crate) fn read_ethereum_ws_url() -> Option<String> {
+    dotenv().ok();
+    fixtures::read_endpoint("ETHEREUM_WS_URL")
 }
@@ -0,0 +1,303 @@
+//! OP-stack L2 domains.
+//!
+//! An OP-stack chain periodically commits an output root of its state to Ethereum, either to the
+//! `l2Outputs` array of its L2OutputOracle or, on chains with fault proofs, as the anchor of its
+//! AnchorStateRegistry. L2 state is proven in two steps: the output root is proven against the
+//! Ethereum state root with an Ethereum storage proof, then the L2 account and storage proofs are
+//! proven against the L2 state root the output root commits to.
+
+use std::{env, str::FromStr};
+
+use alloy::primitives::{Address, B256, U256, address, keccak256};
+use coprocessor_circuit_types::{
+    ETHEREUM_DOMAIN_ID, OpStackOutputRootPreimage, OpStackStorageProofs, OutputRootKind,
+};
+use futures::{StreamExt, stream};
+
+use crate::{
+    clients::{BlockSelection, EthereumClient, OpStackClient},
+    coprocessor::{EthereumKey, collect_proofs, get_evm_account_proofs, group_by_contract},
+    domains::domain_env_var,
+    error::{CoprocessorError, Result},
+};
+
+/// Storage slot of the `l2Outputs` array of the L2OutputOracle
+const L2_OUTPUTS_SLOT: u64 = 3;
+/// Storage slot of the `anchors` mapping of the AnchorStateRegistry
+const ANCHORS_SLOT: u64 = 1;
+/// The `L2ToL1MessagePasser` predeploy, whose storage root is part of every output root
+const MESSAGE_PASSER: Address = address!("4200000000000000000000000000000000000016");
+
+/// The L1 contract the output roots of an L2 are read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputRootSource {
+    /// The `l2Outputs` array of an L2OutputOracle
+    L2OutputOracle {
+        address: String,
+        /// The output to prove, the latest one if `None`
+        index: Option<u64>,
+    },
+    /// The `anchors` mapping of an AnchorStateRegistry, used by chains with fault proofs
+    AnchorStateRegistry { address: String, game_type: u32 },
+}
+
+/// Where the output root of an L2 is stored at a given Ethereum height
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct OutputRootLocation {
+    /// The storage slot holding the output root
+    output_root_slot: B256,
+    /// The L2 block number the output root was proposed for
+    l2_block_number: u64,
+}
+
+impl OutputRootSource {
+    /// Reads the output root source of an L2 from the `<DOMAIN_ID>_*` environment variables
+    ///
+    /// Either `<DOMAIN_ID>_L2_OUTPUT_ORACLE`, optionally with `<DOMAIN_ID>_OUTPUT_INDEX`, or
+    /// `<DOMAIN_ID>_ANCHOR_STATE_REGISTRY`, optionally with `<DOMAIN_ID>_GAME_TYPE` (defaults
+    /// to 0, the permissionless cannon game), has to be set.
+    pub fn from_env(domain_id: &str) -> Result<Self> {
+        let read = |name: &str| env::var(domain_env_var(domain_id, name)).ok();
+        let parse = |name: &str| {
+            read(name)
+                .map(|value| {
+                    value.parse::<u64>().map_err(|_| {
+                        CoprocessorError::Config(format!(
+                            "Invalid {}",
+                            domain_env_var(domain_id, name)
+                        ))
+                    })
+                })
+                .transpose()
+        };
+        match (read("L2_OUTPUT_ORACLE"), read("ANCHOR_STATE_REGISTRY")) {
+            (Some(address), None) => Ok(Self::L2OutputOracle {
+                address,
+                index: parse("OUTPUT_INDEX")?,
+            }),
+            (None, Some(address)) => Ok(Self::AnchorStateRegistry {
+                address,
+                game_type: parse("GAME_TYPE")?.unwrap_or(0) as u32,
+            }),
+            _ => Err(CoprocessorError::Config(format!(
+                "Set either {} or {}",
+                domain_env_var(domain_id, "L2_OUTPUT_ORACLE"),
+                domain_env_var(domain_id, "ANCHOR_STATE_REGISTRY")
+            ))),
+        }
+    }
+
+    /// The address of the L1 contract
+    pub fn address(&self) -> &str {
+        match self {
+            Self::L2OutputOracle { address, .. } | Self::AnchorStateRegistry { address, .. } => {
+                address
+            }
+        }
+    }
+
+    /// The layout of the output roots stored in the L1 contract
+    pub fn kind(&self) -> OutputRootKind {
+        match self {
+            Self::L2OutputOracle { .. } => OutputRootKind::L2OutputOracle,
+            Self::AnchorStateRegistry { .. } => OutputRootKind::AnchorStateRegistry,
+        }
+    }
+
+    /// Finds the slot of the output root to prove and the L2 block it belongs to
+    ///
+    /// Both are read without a proof here, the slot holding the L2 block number is proven
+    /// together with the output root.
+    async fn locate(
+        &self,
+        ethereum_client: &EthereumClient,
+        ethereum_height: u64,
+    ) -> Result<OutputRootLocation> {
+        let address = Address::from_str(self.address())
+            .map_err(|e| CoprocessorError::decoding("output root contract address", e))?;
+        let output_root_slot = match self {
+            Self::L2OutputOracle { index, .. } => {
+                let index = match index {
+                    Some(index) => *index,
+                    None => {
+                        // the length of the array is stored in its slot
+                        let outputs: u64 = ethereum_client
+                            .get_storage_at(address, U256::from(L2_OUTPUTS_SLOT), ethereum_height)
+                            .await?
+                            .try_into()
+                            .map_err(|e| CoprocessorError::decoding("L2 output count", e))?;
+                        outputs.checked_sub(1).ok_or_else(|| {
+                            CoprocessorError::Verification("No L2 output was proposed".to_string())
+                        })?
+                    }
+                };
+                l2_output_slot(index)
+            }
+            Self::AnchorStateRegistry { game_type, .. } => anchor_slot(*game_type),
+        };
+        // the L2 block number is stored in the slot following the output root
+        let block_number_word = ethereum_client
+            .get_storage_at(address, output_root_slot + U256::from(1), ethereum_height)
+            .await?;
+        let l2_block_number = match self {
+            // packed after the 128 bit timestamp of the output proposal
+            Self::L2OutputOracle { .. } => block_number_word >> 128,
+            Self::AnchorStateRegistry { .. } => block_number_word,
+        };
+        Ok(OutputRootLocation {
+            output_root_slot: output_root_slot.into(),
+            l2_block_number: l2_block_number
+                .try_into()
+                .map_err(|e| CoprocessorError::decoding("L2 block number", e))?,
+        })
+    }
+}
+
+/// The slot of the output root of output `index` in the `l2Outputs` array, every output
+/// proposal taking two slots
+fn l2_output_slot(index: u64) -> U256 {
+    let array_start = keccak256(U256::from(L2_OUTPUTS_SLOT).to_be_bytes::<32>());
+    U256::from_be_bytes(array_start.0) + U256::from(index) * U256::from(2)
+}
+
+/// The slot of the output root anchoring games of `game_type` in the `anchors` mapping
+fn anchor_slot(game_type: u32) -> U256 {
+    let key = [
+        U256::from(game_type).to_be_bytes::<32>(),
+        U256::from(ANCHORS_SLOT).to_be_bytes::<32>(),
+    ]
+    .concat();
+    U256::from_be_bytes(keccak256(key).0)
+}
+
+/// Fetches L2 storage proofs of an OP-stack domain together with the proof of the output root
+/// they are verified against
+pub struct OpStackMerkleProofProvider {
+    /// The client of the L2
+    pub client: OpStackClient,
+    /// The Ethereum client the output roots are proven with
+    pub ethereum_client: EthereumClient,
+}
+
+impl OpStackMerkleProofProvider {
+    /// Fetches the proofs of L2 storage slots against the output root stored on Ethereum
+    ///
+    /// The proofs are verified before they are returned, against the state root of the
+    /// Ethereum block at `ethereum_height`.
+    ///
+    /// # Arguments
+    /// * `ethereum_height` - The Ethereum execution block height the output root is read at,
+    ///   usually the one proven by the Helios domain
+    /// * `keys` - The L2 storage slots with the address of their contract
+    /// * `concurrency` - The maximum number of L2 contracts proven concurrently
+    ///
+    /// # Errors
+    /// `CoprocessorError::StorageProofs` listing every contract whose proofs could not be fetched
+    pub async fn get_storage_proofs(
+        &self,
+        ethereum_height: u64,
+        keys: Vec<(EthereumKey, String)>,
+        concurrency: usize,
+    ) -> Result<OpStackStorageProofs> {
+        let domain_id = &self.client.config.domain_id;
+        let source = &self.client.config.output_root_source;
+        let location = source
+            .locate(&self.ethereum_client, ethereum_height)
+            .await?;
+        let output_root_proof = get_evm_account_proofs(
+            ETHEREUM_DOMAIN_ID,
+            &self.ethereum_client.transport,
+            &self.ethereum_client.cache,
+            source.address(),
+            &[
+                location.output_root_slot.to_vec(),
+                (U256::from_be_bytes(location.output_root_slot.0) + U256::from(1))
+                    .to_be_bytes_vec(),
+            ],
+            ethereum_height,
+        )
+        .await?;
+        // the output root was proposed on finalized L1 blocks, so the L2 block is final too
+        let l2_block = self.client.get_block(location.l2_block_number).await?;
+        let message_passer_proofs = get_evm_account_proofs(
+            domain_id,
+            &self.client.transport,
+            &self.client.cache,
+            &MESSAGE_PASSER.to_string(),
+            &[],
+            location.l2_block_number,
+        )
+        .await?;
+        let contracts = group_by_contract(keys);
+        let results = stream::iter(&contracts)
+            .map(|(address, keys)| {
+                get_evm_account_proofs(
+                    domain_id,
+                    &self.client.transport,
+                    &self.client.cache,
+                    address,
+                    keys,
+                    location.l2_block_number,
+                )
+            })
+            .buffered(concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        let mut failures = Vec::new();
+        let account_proofs = collect_proofs(
+            domain_id,
+            &contracts,
+            results,
+            |(address, keys)| format!("{} slots of {}", keys.len(), address),
+            &mut failures,
+        );
+        if !failures.is_empty() {
+            return Err(CoprocessorError::StorageProofs(failures));
+        }
+        let proofs = OpStackStorageProofs {
+            output_root_kind: source.kind(),
+            output_root_slot: location.output_root_slot.to_vec(),
+            output_root_proof,
+            output_root_preimage: OpStackOutputRootPreimage {
+                version: vec![0; 32],
+                state_root: l2_block.state_root,
+                message_passer_storage_root: message_passer_proofs.storage_root,
+                block_hash: l2_block.hash,
+            },
+            l2_block_number: location.l2_block_number,
+            account_proofs,
+        };
+        // fail here rather than in the application circuit if the L2 node disagrees with L1
+        let ethereum_block = self
+            .ethereum_client
+            .get_block(BlockSelection::Number(ethereum_height))
+            .await?;
+        proofs.verify(&ethereum_block.state_root).map_err(|e| {
+            CoprocessorError::Verification(format!("{} output root proofs: {}", domain_id, e))
+        })?;
+        Ok(proofs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{anchor_slot, l2_output_slot};
+    use alloy::primitives::{U256, keccak256};
+
+    #[test]
+    fn test_output_root_slots() {
+        // keccak256(uint256(3)), the first slot of the `l2Outputs` array
+        let array_start = U256::from_str_radix(
+            "c2575a0e9e593c00f959f8c92f12db2869c3395a3b0502d05e2516446f71f85b",
+            16,
+        )
+        .unwrap();
+        assert_eq!(l2_output_slot(0), array_start);
+        // every output proposal takes two slots
+        assert_eq!(l2_output_slot(5), array_start + U256::from(10));
+
+        let mut key = [0u8; 64];
+        key[63] = 1;
+        assert_eq!(anchor_slot(0), U256::from_be_bytes(keccak256(key).0));
+    }
+}
@@ -0,0 +1,339 @@
+//! Durable storage for the coprocessor SMT.
+//!
+//! `MemorySmt` never discards nodes, so every root it has produced stays openable as long as
+//! the inserts that built it are known. `PersistentSmt` journals each insert into an embedded
+//! sled database and replays the journal into a fresh `MemorySmt` when the data directory is
+//! reopened, which restores the tree together with the last committed root.
+//!
+//! Every committed root is also recorded in an archive together with the domain heights it
+//! holds, so that openings can still be produced against older roots after newer updates.
+//! The checkpoint of the trusted light-client state is written in the same transaction.
+
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, anyhow};
+use sled::{Transactional, transaction::ConflictableTransactionError};
+use valence_coprocessor_core::{MemorySmt, SmtOpening};
+
+/// Name of the sled tree holding the ordered insert journal
+const JOURNAL_TREE: &[u8] = b"smt_journal";
+/// Name of the sled tree holding the archive of committed roots, in commit order
+const ARCHIVE_TREE: &[u8] = b"root_archive";
+/// Key under which the last committed root is stored in the default sled tree
+const COMMITTED_ROOT_KEY: &[u8] = b"committed_root";
+/// Key under which the checkpoint of the last committed root is stored in the default sled tree
+const CHECKPOINT_KEY: &[u8] = b"checkpoint";
+
+/// A single SMT insert as recorded in the journal
+#[derive(Debug, Clone, borsh::BorshSerialize, borsh::BorshDeserialize)]
+pub struct JournalEntry {
+    /// The root the value was inserted into
+    pub previous_root: [u8; 32],
+    /// The SMT context of the insert
+    pub context: String,
+    /// The SMT key
+    pub key: Vec<u8>,
+    /// The inserted value
+    pub data: Vec<u8>,
+    /// The root produced by the insert, used to detect a diverging replay
+    pub root: [u8; 32],
+}
+
+/// A committed SMT root as recorded in the archive
+#[derive(Debug, Clone, PartialEq, Eq, borsh::BorshSerialize, borsh::BorshDeserialize)]
+pub struct ArchivedRoot {
+    /// The committed root
+    pub root: [u8; 32],
+    /// The root committed before it
+    pub previous_root: [u8; 32],
+    /// The height (or beacon slot) stored for each domain under this root, keyed by domain id
+    pub domain_heights: BTreeMap<String, u64>,
+    /// Unix timestamp of the commit in seconds
+    pub timestamp: u64,
+}
+
+/// A `MemorySmt` backed by an on-disk insert journal
+pub struct PersistentSmt {
+    /// The in-memory tree rebuilt from the journal
+    tree: MemorySmt,
+    /// The sled database holding the journal and the committed root
+    db: sled::Db,
+    /// The ordered insert journal
+    journal: sled::Tree,
+    /// The archive of committed roots
+    archive: sled::Tree,
+    /// The last root that was committed with `commit`
+    committed_root: [u8; 32],
+    /// Inserts made by this process since the last commit, in order
+    pending: Vec<JournalEntry>,
+}
+
+impl PersistentSmt {
+    /// Opens (or creates) the SMT store in `data_dir` and replays its journal
+    ///
+    /// # Arguments
+    /// * `data_dir` - The directory holding the sled database
+    ///
+    /// # Returns
+    /// The restored tree, positioned at the last committed root (`[0; 32]` for a new store)
+    pub fn open(data_dir: impl AsRef<Path>) -> Result<Self> {
+        let db = sled::open(data_dir.as_ref()).with_context(|| {
+            format!(
+                "Failed to open SMT store at {}",
+                data_dir.as_ref().display()
+            )
+        })?;
+        let journal = db.open_tree(JOURNAL_TREE)?;
+        let archive = db.open_tree(ARCHIVE_TREE)?;
+        let tree = MemorySmt::default();
+        // sled iterates in key order and the journal keys are big-endian sequence numbers
+        for entry in journal.iter() {
+            let (_, value) = entry?;
+            let entry: JournalEntry = borsh::from_slice(&value)?;
+            let root = tree.insert(entry.previous_root, &entry.context, &entry.key, entry.data)?;
+            if root != entry.root {
+                return Err(anyhow!(
+                    "SMT journal replay diverged: expected root {}, got {}",
+                    hex::encode(entry.root),
+                    hex::encode(root)
+                ));
+            }
+        }
+        let committed_root = match db.get(COMMITTED_ROOT_KEY)? {
+            Some(root) => root
+                .as_ref()
+                .try_into()
+                .map_err(|_| anyhow!("Invalid committed SMT root in store"))?,
+            None => [0; 32],
+        };
+        Ok(Self {
+            tree,
+            db,
+            journal,
+            archive,
+            committed_root,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Inserts a value into the tree and appends the insert to the journal
+    ///
+    /// # Returns
+    /// The new SMT root
+    pub fn insert(
+        &mut self,
+        root: [u8; 32],
+        context: &str,
+        key: &[u8],
+        data: Vec<u8>,
+    ) -> Result<[u8; 32]> {
+        let new_root = self.tree.insert(root, context, key, data.clone())?;
+        let entry = JournalEntry {
+            previous_root: root,
+            context: context.to_string(),
+            key: key.to_vec(),
+            data,
+            root: new_root,
+        };
+        let sequence = self.db.generate_id()?;
+        self.journal
+            .insert(sequence.to_be_bytes(), borsh::to_vec(&entry)?)?;
+        self.pending.push(entry);
+        Ok(new_root)
+    }
+
+    /// Returns the opening of `key` in `context` against `root`
+    pub fn get_opening(
+        &self,
+        context: &str,
+        root: [u8; 32],
+        key: &[u8],
+    ) -> Result<Option<SmtOpening>> {
+        self.tree.get_opening(context, root, key)
+    }
+
+    /// Marks `root` as the last committed root, archives it together with its checkpoint and
+    /// flushes the store to disk
+    ///
+    /// The root, its archive entry and the checkpoint are written in a single transaction.
+    ///
+    /// # Arguments
+    /// * `root` - The root proven by the coprocessor update
+    /// * `domain_heights` - The height stored for each domain under `root`
+    /// * `checkpoint` - The encoded checkpoint of the trusted state under `root`
+    pub fn commit(
+        &mut self,
+        root: [u8; 32],
+        domain_heights: BTreeMap<String, u64>,
+        checkpoint: &[u8],
+    ) -> Result<()> {
+        let archived_root = ArchivedRoot {
+            root,
+            previous_root: self.committed_root,
+            domain_heights,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+        let sequence = self.db.generate_id()?;
+        let archived_root = borsh::to_vec(&archived_root)?;
+        (&self.archive, &*self.db)
+            .transaction(|(archive, db)| {
+                archive.insert(&sequence.to_be_bytes()[..], archived_root.as_slice())?;
+                db.insert(COMMITTED_ROOT_KEY, &root)?;
+                db.insert(CHECKPOINT_KEY, checkpoint)?;
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e| anyhow!("Failed to commit root {}: {:?}", hex::encode(root), e))?;
+        self.db.flush()?;
+        self.committed_root = root;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Drops the inserts made since the last commit from the next update
+    ///
+    /// The discarded nodes stay in the tree, but the next update starts over from the committed
+    /// root again.
+    ///
+    /// # Returns
+    /// The last committed root
+    pub fn discard_pending(&mut self) -> [u8; 32] {
+        self.pending.clear();
+        self.committed_root
+    }
+
+    /// The inserts made since the last commit, in the order they were applied
+    pub fn pending_insertions(&self) -> &[JournalEntry] {
+        &self.pending
+    }
+
+    /// All archived roots, oldest first
+    pub fn archived_roots(&self) -> Result<Vec<ArchivedRoot>> {
+        self.archive
+            .iter()
+            .map(|entry| {
+                let (_, value) = entry?;
+                Ok(borsh::from_slice(&value)?)
+            })
+            .collect()
+    }
+
+    /// Looks up a committed root in the archive
+    pub fn get_archived_root(&self, root: [u8; 32]) -> Result<Option<ArchivedRoot>> {
+        Ok(self
+            .archived_roots()?
+            .into_iter()
+            .rev()
+            .find(|archived_root| archived_root.root == root))
+    }
+
+    /// Finds the newest archived root that stores `height` for a domain
+    pub fn find_archived_root(&self, domain_id: &str, height: u64) -> Result<Option<ArchivedRoot>> {
+        Ok(self
+            .archived_roots()?
+            .into_iter()
+            .rev()
+            .find(|archived_root| archived_root.domain_heights.get(domain_id) == Some(&height)))
+    }
+
+    /// Returns the opening of `key` in `context` against an archived root
+    ///
+    /// Fails if `root` was never committed, so that openings are only handed out for roots
+    /// that were proven by a coprocessor update.
+    pub fn get_archived_opening(
+        &self,
+        context: &str,
+        root: [u8; 32],
+        key: &[u8],
+    ) -> Result<Option<SmtOpening>> {
+        if self.get_archived_root(root)?.is_none() {
+            return Err(anyhow!("Root {} is not in the archive", hex::encode(root)));
+        }
+        self.get_opening(context, root, key)
+    }
+
+    /// The checkpoint committed with the last root, `None` if nothing has been committed yet
+    pub fn checkpoint(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .db
+            .get(CHECKPOINT_KEY)?
+            .map(|checkpoint| checkpoint.to_vec()))
+    }
+
+    /// The last committed root, `[0; 32]` if nothing has been committed yet
+    pub fn committed_root(&self) -> [u8; 32] {
+        self.committed_root
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PersistentSmt;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_reopen_at_committed_root() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let key = [7u8; 32];
+        let root = {
+            let mut smt = PersistentSmt::open(data_dir.path()).unwrap();
+            assert_eq!(smt.committed_root(), [0; 32]);
+            let root = smt.insert([0; 32], "demo", &key, vec![1, 2, 3]).unwrap();
+            smt.commit(root, BTreeMap::new(), b"checkpoint").unwrap();
+            // an uncommitted insert must not move the committed root
+            smt.insert(root, "demo", &key, vec![4, 5, 6]).unwrap();
+            root
+        };
+        let smt = PersistentSmt::open(data_dir.path()).unwrap();
+        assert_eq!(smt.committed_root(), root);
+        assert_eq!(smt.checkpoint().unwrap(), Some(b"checkpoint".to_vec()));
+        let opening = smt.get_opening("demo", root, &key).unwrap().unwrap();
+        assert_eq!(opening.data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_openings_at_archived_roots() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let mut smt = PersistentSmt::open(data_dir.path()).unwrap();
+        let key = [7u8; 32];
+        let first_root = smt.insert([0; 32], "demo", &key, vec![1]).unwrap();
+        smt.commit(
+            first_root,
+            BTreeMap::from([("neutron".to_string(), 10)]),
+            &[],
+        )
+        .unwrap();
+        let second_root = smt.insert(first_root, "demo", &key, vec![2]).unwrap();
+        smt.commit(
+            second_root,
+            BTreeMap::from([("neutron".to_string(), 20)]),
+            &[],
+        )
+        .unwrap();
+
+        let archived_root = smt.find_archived_root("neutron", 10).unwrap().unwrap();
+        assert_eq!(archived_root.root, first_root);
+        assert_eq!(archived_root.previous_root, [0; 32]);
+        let opening = smt
+            .get_archived_opening("demo", first_root, &key)
+            .unwrap()
+            .unwrap();
+        assert_eq!(opening.data, vec![1]);
+        assert_eq!(
+            smt.get_archived_root(second_root)
+                .unwrap()
+                .unwrap()
+                .previous_root,
+            first_root
+        );
+        // roots that were never committed are not served
+        let uncommitted_root = smt.insert(second_root, "demo", &key, vec![3]).unwrap();
+        assert!(
+            smt.get_archived_opening("demo", uncommitted_root, &key)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! Retrying RPC transport shared by the chain clients and proof providers.
+//!
+//! Public testnet RPCs regularly drop or stall requests. A `Transport` holds the endpoints of one
+//! chain and runs every request with a timeout, fails over to the next endpoint on transient
+//! errors and backs off exponentially between rounds over all endpoints.
+//!
+//! The client of every endpoint is constructed once when the transport is created, so that its
+//! connection pool is reused by every request and by every clone of the transport.
+
+use std::{
+    env,
+    future::Future,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use dotenvy::dotenv;
+
+use crate::error::{CoprocessorError, Result};
+
+/// Retry behaviour of a `Transport`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryConfig {
+    /// Number of additional rounds over all endpoints after the first round failed
+    pub max_retries: u32,
+    /// Delay before the first retry round, doubled for every further round
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between rounds
+    pub max_backoff: Duration,
+    /// Timeout of a single request to a single endpoint
+    pub request_timeout: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Reads the retry configuration from environment variables, falling back to the defaults
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        let default = Self::default();
+        let read = |name: &str| -> Option<u64> {
+            env::var(name).ok().map(|value| {
+                value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid {}!", name))
+            })
+        };
+        Self {
+            max_retries: read("RPC_MAX_RETRIES")
+                .map(|retries| retries as u32)
+                .unwrap_or(default.max_retries),
+            initial_backoff: read("RPC_INITIAL_BACKOFF_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.initial_backoff),
+            max_backoff: read("RPC_MAX_BACKOFF_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.max_backoff),
+            request_timeout: read("RPC_TIMEOUT_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.request_timeout),
+        }
+    }
+
+    /// The delay before retry round `round`, starting at 1
+    fn backoff(&self, round: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(round - 1))
+            .min(self.max_backoff)
+    }
+}
+
+/// An RPC endpoint together with its client
+#[derive(Debug)]
+struct Endpoint<C> {
+    url: String,
+    client: C,
+}
+
+/// A list of interchangeable RPC endpoints of one chain and their clients
+///
+/// Clones are cheap and share the clients as well as the endpoint that served the last
+/// successful request, so that all users of a transport stop hitting an endpoint once it failed.
+#[derive(Debug)]
+pub struct Transport<C = String> {
+    endpoints: Arc<Vec<Endpoint<C>>>,
+    config: RetryConfig,
+    /// Index of the endpoint that served the last successful request
+    preferred: Arc<AtomicUsize>,
+}
+
+impl<C> Clone for Transport<C> {
+    fn clone(&self) -> Self {
+        Self {
+            endpoints: self.endpoints.clone(),
+            config: self.config.clone(),
+            preferred: self.preferred.clone(),
+        }
+    }
+}
+
+impl Transport {
+    /// Creates a transport over `urls` whose requests are given the endpoint url
+    pub fn new(urls: Vec<String>, config: RetryConfig) -> Result<Self> {
+        Self::connect(urls, config, |url| Ok(url.to_string()))
+    }
+}
+
+impl<C: Clone> Transport<C> {
+    /// Creates a transport over `urls`, which are tried in order
+    ///
+    /// # Arguments
+    /// * `urls` - The endpoint urls
+    /// * `config` - The retry behaviour
+    /// * `connect` - Constructs the client of an endpoint, called once per url
+    pub fn connect(
+        urls: Vec<String>,
+        config: RetryConfig,
+        connect: impl Fn(&str) -> Result<C>,
+    ) -> Result<Self> {
+        if urls.is_empty() {
+            return Err(CoprocessorError::Config(
+                "A transport needs at least one endpoint".to_string(),
+            ));
+        }
+        let endpoints = urls
+            .into_iter()
+            .map(|url| {
+                Ok(Endpoint {
+                    client: connect(&url)?,
+                    url,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            endpoints: Arc::new(endpoints),
+            config,
+            preferred: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// The url of the endpoint the next request is sent to first
+    pub fn endpoint(&self) -> &str {
+        &self.endpoints[self.preferred.load(Ordering::Relaxed)].url
+    }
+
+    /// Runs `request` against the endpoints until it succeeds
+    ///
+    /// Retryable errors and timeouts fail over to the next endpoint. Once every endpoint failed,
+    /// the transport waits for the backoff delay and starts another round, up to `max_retries`
+    /// times. Any other error is returned immediately.
+    ///
+    /// # Arguments
+    /// * `context` - Describes the request in errors
+    /// * `request` - Sends the request with the client of the endpoint it is given
+    ///
+    /// # Returns
+    /// The first successful response, or the error of the last attempt
+    pub async fn call<T, F, Fut>(&self, context: &str, request: F) -> Result<T>
+    where
+        F: Fn(C) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut last_error = None;
+        for round in 0..=self.config.max_retries {
+            if round > 0 {
+                tokio::time::sleep(self.config.backoff(round)).await;
+            }
+            let first = self.preferred.load(Ordering::Relaxed);
+            for offset in 0..self.endpoints.len() {
+                let index = (first + offset) % self.endpoints.len();
+                let response = tokio::time::timeout(
+                    self.config.request_timeout,
+                    request(self.endpoints[index].client.clone()),
+                )
+                .await
+                .unwrap_or_else(|_| {
+                    Err(CoprocessorError::rpc(
+                        context,
+                        anyhow!("Timed out after {:?}", self.config.request_timeout),
+                    ))
+                });
+                match response {
+                    Ok(value) => {
+                        self.preferred.store(index, Ordering::Relaxed);
+                        return Ok(value);
+                    }
+                    Err(error) if error.is_retryable() => last_error = Some(error),
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| CoprocessorError::rpc(context, anyhow!("No attempts"))))
+    }
+}
+
+/// Splits a comma separated list of endpoints
+pub fn parse_endpoints(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|endpoint| !endpoint.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RetryConfig, Transport, parse_endpoints};
+    use crate::error::CoprocessorError;
+    use anyhow::anyhow;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    fn test_config() -> RetryConfig {
+        RetryConfig {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            request_timeout: Duration::from_millis(50),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failover_and_retries() {
+        let transport = Transport::new(
+            parse_endpoints("http://down, http://stalled ,http://up"),
+            test_config(),
+        )
+        .unwrap();
+        let attempts = AtomicUsize::new(0);
+        let response = transport
+            .call("test", |endpoint| {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    match endpoint.as_str() {
+                        "http://down" => Err(CoprocessorError::rpc("test", anyhow!("down"))),
+                        "http://stalled" => {
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            Ok(0)
+                        }
+                        // the healthy endpoint only answers on the second round
+                        _ if attempt < 3 => Err(CoprocessorError::rpc("test", anyhow!("busy"))),
+                        _ => Ok(attempt),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(response, 5);
+        // the endpoint that answered is tried first from now on
+        assert_eq!(transport.endpoint(), "http://up");
+
+        // errors that are not transient are not retried
+        let attempts = AtomicUsize::new(0);
+        let response: Result<(), _> = transport
+            .call("test", |_| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async { Err(CoprocessorError::Verification("invalid".to_string())) }
+            })
+            .await;
+        assert!(matches!(response, Err(CoprocessorError::Verification(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -2,10 +2,12 @@
 /// This module provides the core data structures and functions needed for cross-chain message verification
 /// between Ethereum and Neutron chains using zero-knowledge proofs.
 use beacon::types::electra::{ElectraBlockBodyRoots, ElectraBlockHeader};
-use ethereum_merkle_proofs::merkle_lib::types::EthereumMerkleProof;
+pub use coprocessor_circuit_types::{
+    DomainOpenings, ETHEREUM_DOMAIN_ID, EthereumAccountProofs, NEUTRON_DOMAIN_ID,
+    find_domain_openings,
+};
 use ics23_merkle_proofs::merkle_lib::types::Ics23MerkleProof;
 use serde::{Deserialize, Serialize};
-use valence_coprocessor_core::SmtOpening;
 /// Inputs for the mailbox application circuit that contains all necessary merkle proofs
 /// and SMT openings for verifying messages across different domains.
 ///
@@ -13,18 +15,13 @@ use valence_coprocessor_core::SmtOpening;
 /// including merkle proofs for both chains and their respective block headers.
 #[derive(Serialize, Deserialize)]
 pub struct MailboxApplicationCircuitInputs {
-    /// Ethereum storage proofs for message verification, containing account proofs, storage proofs, and account addresses
-    pub ethereum_storage_proofs: Vec<(EthereumMerkleProof, EthereumMerkleProof, Vec<u8>)>,
+    /// Ethereum storage proofs for message verification, one account proof per contract
+    /// together with the storage proofs of all its slots
+    pub ethereum_storage_proofs: Vec<EthereumAccountProofs>,
     /// Neutron storage proofs for message verification
     pub neutron_storage_proofs: Vec<Ics23MerkleProof>,
-    /// SMT opening containing the Neutron chain height
-    pub neutron_height_opening: SmtOpening,
-    /// SMT opening containing the Ethereum chain height
-    pub ethereum_height_opening: SmtOpening,
-    /// SMT opening containing the Neutron chain root
-    pub neutron_root_opening: SmtOpening,
-    /// SMT opening containing the Ethereum chain root
-    pub ethereum_root_opening: SmtOpening,
+    /// SMT openings containing the heights and roots of the Neutron and Ethereum domains
+    pub domain_openings: Vec<DomainOpenings>,
     /// Tendermint block header from Neutron chain
     pub neutron_block_header: tendermint::block::Header,
     /// Electra block header from Ethereum chain